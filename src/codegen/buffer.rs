@@ -10,7 +10,7 @@ impl Buffer {
     }
 
     pub(crate) fn write_prepend(&mut self, v: &str) {
-        self.inner = vec![vec![v.to_owned()], self.inner.clone()].concat();
+        self.inner.insert(0, v.to_owned());
     }
 
     pub(crate) fn write(&mut self, v: &str) {
@@ -22,10 +22,32 @@ impl Buffer {
     }
 
     pub(crate) fn write_quoted(&mut self, v: &str) {
-        self.inner.push(format!("\"{v}\""));
+        self.inner.push(format!("\"{}\"", escape_js_string(v, '"')));
     }
 }
 
+/// Escapes `v` for a JavaScript string literal delimited by `quote`. Template
+/// literals (`` ` ``) also get their `$` escaped so that nothing in `v` is
+/// interpolated.
+pub(crate) fn escape_js_string(v: &str, quote: char) -> String {
+    let mut out = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '$' if quote == '`' => out.push_str("\\$"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
 impl fmt::Display for Buffer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for chunk in &self.inner {
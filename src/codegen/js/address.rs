@@ -0,0 +1,174 @@
+use crate::codegen::js::test::{generate_negatable, generate_values_match};
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+use sieve::compiler::grammar as sieve_grammar;
+
+/// Returns every address found in the given headers, group members included.
+const SIEVE_ADDRESSES: &str = r#"
+function sieveAddresses(parsedMessage, headerNames) {
+  const names = headerNames.map((name) => name.toLowerCase());
+  return parsedMessage.headers
+    .filter((header) => names.includes(header.key))
+    .flatMap((header) => addressParser(header.value))
+    .flatMap((address) => address.group || [address]);
+}
+"#;
+
+/// Extracts an address part, returns null when the part doesn't exist (a
+/// :detail without separator).
+const SIEVE_ADDRESS_PART: &str = r#"
+function sieveAddressPart(address, part) {
+  const value = address.address || "";
+  const at = value.lastIndexOf("@");
+  const localpart = at === -1 ? value : value.slice(0, at);
+  const separator = localpart.indexOf("+");
+  switch (part) {
+    case "localpart":
+      return localpart;
+    case "domain":
+      return at === -1 ? "" : value.slice(at + 1);
+    case "user":
+      return separator === -1 ? localpart : localpart.slice(0, separator);
+    case "detail":
+      return separator === -1 ? null : localpart.slice(separator + 1);
+    case "name":
+      return address.name || "";
+    default:
+      return value;
+  }
+}
+"#;
+
+pub(crate) fn generate_test_address(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::tests::test_address::TestAddress,
+) -> Result<(), BoxError> {
+    if node.index.is_some() {
+        return Err("unsupported :index in address test".into());
+    }
+
+    ctx.add_helper(SIEVE_ADDRESSES);
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_address_match(
+            ctx,
+            &node.address_part,
+            &node.match_type,
+            &node.comparator,
+            &node.key_list,
+            |ctx| {
+                ctx.buffer.write("sieveAddresses(parsedMessage, [");
+                for header in &node.header_list {
+                    generate_value(ctx, header)?;
+                    ctx.buffer.write(",");
+                }
+                ctx.buffer.write("])");
+                Ok(())
+            },
+        )
+    })
+}
+
+/// Generates the match of the address part of every address of the
+/// JavaScript array written by `addresses` against any of the keys.
+pub(crate) fn generate_address_match<'a>(
+    ctx: &mut CodeGen<'a>,
+    address_part: &sieve_grammar::AddressPart,
+    match_type: &sieve_grammar::MatchType,
+    comparator: &sieve_grammar::Comparator,
+    keys: &[sieve::compiler::Value],
+    addresses: impl FnOnce(&mut CodeGen<'a>) -> Result<(), BoxError>,
+) -> Result<(), BoxError> {
+    let part = match address_part {
+        sieve_grammar::AddressPart::All => "all",
+        sieve_grammar::AddressPart::LocalPart => "localpart",
+        sieve_grammar::AddressPart::Domain => "domain",
+        sieve_grammar::AddressPart::User => "user",
+        sieve_grammar::AddressPart::Detail => "detail",
+        sieve_grammar::AddressPart::Name => "name",
+    };
+
+    ctx.add_helper(SIEVE_ADDRESS_PART);
+
+    generate_values_match(ctx, match_type, comparator, keys, |ctx| {
+        addresses(ctx)?;
+        ctx.buffer.write(&format!(
+            ".map((address) => sieveAddressPart(address, \"{part}\"))"
+        ));
+        if *address_part == sieve_grammar::AddressPart::Detail {
+            ctx.buffer.write(".filter((value) => value !== null)");
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_test_address() {
+        let test_cases = &[
+            (
+                vec!["To"],
+                vec!["a@example.com"],
+                sieve_grammar::AddressPart::All,
+                sieve_grammar::MatchType::Is,
+                r#"sieveAddresses(parsedMessage, ["To",]).map((address) => sieveAddressPart(address, "all")).some((value) => sieveIs(value, "a@example.com", "i;ascii-casemap"))"#,
+            ),
+            (
+                vec!["From", "Sender", "Reply-To"],
+                vec!["example.com", "example.org"],
+                sieve_grammar::AddressPart::Domain,
+                sieve_grammar::MatchType::Is,
+                r#"sieveAddresses(parsedMessage, ["From","Sender","Reply-To",]).map((address) => sieveAddressPart(address, "domain")).some((value) => (sieveIs(value, "example.com", "i;ascii-casemap") || sieveIs(value, "example.org", "i;ascii-casemap")))"#,
+            ),
+            (
+                vec!["Cc", "Bcc", "X-Original-To"],
+                vec!["admin"],
+                sieve_grammar::AddressPart::LocalPart,
+                sieve_grammar::MatchType::Contains,
+                r#"sieveAddresses(parsedMessage, ["Cc","Bcc","X-Original-To",]).map((address) => sieveAddressPart(address, "localpart")).some((value) => sieveContains(value, "admin", "i;ascii-casemap"))"#,
+            ),
+            (
+                vec!["To"],
+                vec!["lists"],
+                sieve_grammar::AddressPart::Detail,
+                sieve_grammar::MatchType::Is,
+                r#"sieveAddresses(parsedMessage, ["To",]).map((address) => sieveAddressPart(address, "detail")).filter((value) => value !== null).some((value) => sieveIs(value, "lists", "i;ascii-casemap"))"#,
+            ),
+            (
+                vec!["To"],
+                vec!["jane"],
+                sieve_grammar::AddressPart::User,
+                sieve_grammar::MatchType::Is,
+                r#"sieveAddresses(parsedMessage, ["To",]).map((address) => sieveAddressPart(address, "user")).some((value) => sieveIs(value, "jane", "i;ascii-casemap"))"#,
+            ),
+        ];
+
+        for (headers, keys, address_part, match_type, expected) in test_cases {
+            let test = sieve_grammar::tests::test_address::TestAddress {
+                header_list: headers
+                    .iter()
+                    .map(|h| sieve::compiler::Value::Text(Arc::new(h.to_string())))
+                    .collect(),
+                key_list: keys
+                    .iter()
+                    .map(|k| sieve::compiler::Value::Text(Arc::new(k.to_string())))
+                    .collect(),
+                address_part: *address_part,
+                match_type: *match_type,
+                comparator: sieve_grammar::Comparator::AsciiCaseMap,
+                index: None,
+                mime_anychild: false,
+                is_not: false,
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_test_address(&mut ctx, &test).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+            assert_eq!(&ctx.helpers[..2], &[SIEVE_ADDRESSES, SIEVE_ADDRESS_PART]);
+        }
+    }
+}
@@ -1,27 +1,100 @@
+use crate::codegen::js::test::{generate_negatable, generate_values_match};
 use crate::codegen::js::{generate_value, BoxError, CodeGen};
 use sieve::compiler::grammar as sieve_grammar;
+use sieve_grammar::tests::test_body::BodyTransform;
+
+/// Returns the body as it appears in the message, without any decoding of the
+/// transfer encoding (RFC 5173, section 5.1).
+const SIEVE_RAW_BODY: &str = r#"
+function sieveRawBody(raw) {
+  const message = new TextDecoder().decode(raw);
+  const separator = /\r?\n\r?\n/.exec(message);
+  return separator === null ? [""] : [message.slice(separator.index + separator[0].length)];
+}
+"#;
+
+/// Returns the decoded text of the message, html is only searched when there's
+/// no plain text alternative and with its tags removed (RFC 5173, section 5.3).
+const SIEVE_TEXT_BODY: &str = r#"
+function sieveTextBody(parsedMessage) {
+  if (parsedMessage.text !== undefined) {
+    return [parsedMessage.text];
+  }
+  if (parsedMessage.html !== undefined) {
+    return [parsedMessage.html.replace(/<[^>]*>/g, " ")];
+  }
+  return [""];
+}
+"#;
+
+/// Returns the decoded content of the parts matching any of the content types,
+/// a type without subtype selects all of its subtypes and an empty type every
+/// part (RFC 5173, section 5.2).
+const SIEVE_CONTENT_BODY: &str = r#"
+function sieveContentBody(parsedMessage, contentTypes) {
+  const types = contentTypes.map((type) => type.toLowerCase());
+  const selected = (mimeType) =>
+    types.some((type) =>
+      type === "" || (type.includes("/") ? mimeType === type : mimeType.split("/")[0] === type),
+    );
+  const parts = [];
+  if (parsedMessage.text !== undefined && selected("text/plain")) {
+    parts.push(parsedMessage.text);
+  }
+  if (parsedMessage.html !== undefined && selected("text/html")) {
+    parts.push(parsedMessage.html);
+  }
+  for (const attachment of parsedMessage.attachments) {
+    if (selected(attachment.mimeType.toLowerCase())) {
+      parts.push(
+        typeof attachment.content === "string"
+          ? attachment.content
+          : new TextDecoder().decode(attachment.content),
+      );
+    }
+  }
+  return parts;
+}
+"#;
 
 pub(crate) fn generate_test_body(
     ctx: &mut CodeGen,
     node: &sieve_grammar::tests::test_body::TestBody,
 ) -> Result<(), BoxError> {
-    if node.body_transform != sieve_grammar::tests::test_body::BodyTransform::Text {
-        return Err(format!("unsupported body_transform: {:?}", node.body_transform).into());
+    if node.include_subject {
+        return Err("unsupported :subject in body test".into());
     }
 
-    for i in 0..node.key_list.len() {
-        let key = &node.key_list[i];
-
-        ctx.buffer.write("raw.includes(");
-        generate_value(ctx, key)?;
-        ctx.buffer.write(")");
-
-        if i < node.key_list.len() - 1 {
-            ctx.buffer.write(" || ");
-        }
-    }
+    let helper = match &node.body_transform {
+        BodyTransform::Raw => SIEVE_RAW_BODY,
+        BodyTransform::Text => SIEVE_TEXT_BODY,
+        BodyTransform::Content(_) => SIEVE_CONTENT_BODY,
+    };
+    ctx.add_helper(helper);
 
-    Ok(())
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_values_match(
+            ctx,
+            &node.match_type,
+            &node.comparator,
+            &node.key_list,
+            |ctx| {
+                match &node.body_transform {
+                    BodyTransform::Raw => ctx.buffer.write("sieveRawBody(raw)"),
+                    BodyTransform::Text => ctx.buffer.write("sieveTextBody(parsedMessage)"),
+                    BodyTransform::Content(content_types) => {
+                        ctx.buffer.write("sieveContentBody(parsedMessage, [");
+                        for content_type in content_types {
+                            generate_value(ctx, content_type)?;
+                            ctx.buffer.write(",");
+                        }
+                        ctx.buffer.write("])");
+                    }
+                }
+                Ok(())
+            },
+        )
+    })
 }
 
 #[cfg(test)]
@@ -32,24 +105,73 @@ mod tests {
 
     #[test]
     fn test_generate_test_body() {
-        let test = sieve_grammar::tests::test_body::TestBody {
-            key_list: vec![
-                sieve::compiler::Value::Text(Arc::new("a".to_owned())),
-                sieve::compiler::Value::Text(Arc::new("b".to_owned())),
-                sieve::compiler::Value::Text(Arc::new("c".to_owned())),
-            ],
-            body_transform: sieve_grammar::tests::test_body::BodyTransform::Text,
-            match_type: sieve_grammar::MatchType::Contains,
-            comparator: sieve_grammar::Comparator::AsciiCaseMap,
-            include_subject: false,
-            is_not: false,
-        };
-        let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
-
-        generate_test_body(&mut ctx, &test).unwrap();
-        assert_eq!(
-            ctx.buffer.to_string(),
-            "raw.includes(\"a\") || raw.includes(\"b\") || raw.includes(\"c\")"
-        );
+        let test_cases = &[
+            (
+                BodyTransform::Text,
+                sieve_grammar::MatchType::Contains,
+                false,
+                SIEVE_TEXT_BODY,
+                r#"sieveTextBody(parsedMessage).some((value) => (sieveContains(value, "a", "i;ascii-casemap") || sieveContains(value, "b", "i;ascii-casemap")))"#,
+            ),
+            (
+                BodyTransform::Text,
+                sieve_grammar::MatchType::Contains,
+                true,
+                SIEVE_TEXT_BODY,
+                r#"!(sieveTextBody(parsedMessage).some((value) => (sieveContains(value, "a", "i;ascii-casemap") || sieveContains(value, "b", "i;ascii-casemap"))))"#,
+            ),
+            (
+                BodyTransform::Raw,
+                sieve_grammar::MatchType::Is,
+                false,
+                SIEVE_RAW_BODY,
+                r#"sieveRawBody(raw).some((value) => (sieveIs(value, "a", "i;ascii-casemap") || sieveIs(value, "b", "i;ascii-casemap")))"#,
+            ),
+            (
+                BodyTransform::Content(vec![
+                    sieve::compiler::Value::Text(Arc::new("text".to_owned())),
+                    sieve::compiler::Value::Text(Arc::new("application/pdf".to_owned())),
+                ]),
+                sieve_grammar::MatchType::Contains,
+                false,
+                SIEVE_CONTENT_BODY,
+                r#"sieveContentBody(parsedMessage, ["text","application/pdf",]).some((value) => (sieveContains(value, "a", "i;ascii-casemap") || sieveContains(value, "b", "i;ascii-casemap")))"#,
+            ),
+        ];
+
+        for (body_transform, match_type, is_not, helper, expected) in test_cases {
+            let test = sieve_grammar::tests::test_body::TestBody {
+                key_list: vec![
+                    sieve::compiler::Value::Text(Arc::new("a".to_owned())),
+                    sieve::compiler::Value::Text(Arc::new("b".to_owned())),
+                ],
+                body_transform: body_transform.clone(),
+                match_type: *match_type,
+                comparator: sieve_grammar::Comparator::AsciiCaseMap,
+                include_subject: false,
+                is_not: *is_not,
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_test_body(&mut ctx, &test).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+            assert_eq!(ctx.helpers[0], *helper);
+        }
+    }
+
+    #[test]
+    fn test_generate_test_body_script() {
+        let script = sieve::Compiler::new()
+            .compile(
+                br#"require ["body", "comparator-i;octet"];
+                if body :raw :comparator "i;octet" :matches "*Content-Transfer-Encoding: base64*" { discard; }"#,
+            )
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+        let js = ctx.generate_js().unwrap();
+
+        assert!(js.contains(
+            r#"sieveRawBody(raw).some((value) => /^.*Content-Transfer-Encoding: base64.*$/su.test(value))"#
+        ));
     }
 }
@@ -0,0 +1,404 @@
+//! The comparators (RFC 4790) shared by every test comparing strings. Only
+//! `i;octet`, `i;ascii-casemap` and `i;ascii-numeric` are supported.
+
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+use sieve::compiler::grammar as sieve_grammar;
+
+/// Orders two strings according to a comparator (RFC 4790, section 9).
+/// `i;ascii-numeric` only looks at the leading digits, strings that don't
+/// start with a digit are equal to each other and greater than any number.
+pub(crate) const SIEVE_COMPARE: &str = r#"
+function sieveCompare(a, b, comparator) {
+  a = String(a);
+  b = String(b);
+  switch (comparator) {
+    case "i;ascii-numeric": {
+      const x = /^[0-9]+/.exec(a);
+      const y = /^[0-9]+/.exec(b);
+      if (x === null || y === null) {
+        return (x === null) - (y === null);
+      }
+      const m = BigInt(x[0]);
+      const n = BigInt(y[0]);
+      return m < n ? -1 : m > n ? 1 : 0;
+    }
+    case "i;ascii-casemap":
+      a = a.replace(/[a-z]+/g, (s) => s.toUpperCase());
+      b = b.replace(/[a-z]+/g, (s) => s.toUpperCase());
+    // falls through
+    default: {
+      const x = [...a];
+      const y = [...b];
+      for (let i = 0; i < Math.min(x.length, y.length); i++) {
+        const order = x[i].codePointAt(0) - y[i].codePointAt(0);
+        if (order !== 0) {
+          return order;
+        }
+      }
+      return x.length - y.length;
+    }
+  }
+}
+"#;
+
+const SIEVE_IS: &str = r#"
+function sieveIs(value, key, comparator) {
+  return sieveCompare(value, key, comparator) === 0;
+}
+"#;
+
+const SIEVE_CONTAINS: &str = r#"
+function sieveContains(value, key, comparator) {
+  value = String(value);
+  key = String(key);
+  if (comparator === "i;ascii-casemap") {
+    value = value.replace(/[a-z]+/g, (s) => s.toUpperCase());
+    key = key.replace(/[a-z]+/g, (s) => s.toUpperCase());
+  }
+  return value.includes(key);
+}
+"#;
+
+pub(crate) fn comparator_name(
+    comparator: &sieve_grammar::Comparator,
+) -> Result<&'static str, BoxError> {
+    match comparator {
+        sieve_grammar::Comparator::Octet => Ok("i;octet"),
+        sieve_grammar::Comparator::AsciiCaseMap => Ok("i;ascii-casemap"),
+        sieve_grammar::Comparator::AsciiNumeric => Ok("i;ascii-numeric"),
+        e => Err(format!("unsupported comparator: {e:?}").into()),
+    }
+}
+
+/// Generates the `:is` comparison of the JavaScript expression `value`
+/// against `key`.
+pub(crate) fn generate_is(
+    ctx: &mut CodeGen,
+    comparator: &sieve_grammar::Comparator,
+    value: &str,
+    key: &sieve::compiler::Value,
+) -> Result<(), BoxError> {
+    let comparator = comparator_name(comparator)?;
+
+    ctx.add_helper(SIEVE_COMPARE);
+    ctx.add_helper(SIEVE_IS);
+
+    ctx.buffer.write(&format!("sieveIs({value}, "));
+    generate_value(ctx, key)?;
+    ctx.buffer.write(&format!(", \"{comparator}\")"));
+
+    Ok(())
+}
+
+/// Generates the `:contains` comparison of the JavaScript expression `value`
+/// against `key`. `i;ascii-numeric` has no notion of substring.
+pub(crate) fn generate_contains(
+    ctx: &mut CodeGen,
+    comparator: &sieve_grammar::Comparator,
+    value: &str,
+    key: &sieve::compiler::Value,
+) -> Result<(), BoxError> {
+    let comparator = match comparator {
+        sieve_grammar::Comparator::AsciiNumeric => {
+            return Err("comparator i;ascii-numeric doesn't support :contains".into())
+        }
+        comparator => comparator_name(comparator)?,
+    };
+
+    ctx.add_helper(SIEVE_CONTAINS);
+
+    ctx.buffer.write(&format!("sieveContains({value}, "));
+    generate_value(ctx, key)?;
+    ctx.buffer.write(&format!(", \"{comparator}\")"));
+
+    Ok(())
+}
+
+/// Pushes `c` so that it only matches itself in a JavaScript regex, see
+/// `push_js_regex_char`.
+pub(crate) const SIEVE_REGEX_CHAR: &str = r#"
+function sieveRegexChar(c, caseMap) {
+  if (caseMap && /^[a-zA-Z]$/.test(c)) {
+    return `[${c.toLowerCase()}${c.toUpperCase()}]`;
+  }
+  return /^[\\^$.|?*+()[\]{}/]$/.test(c) ? `\\${c}` : c;
+}
+"#;
+
+/// Translates a wildcard pattern known only at runtime, like
+/// `sieve_to_js_regex`.
+const SIEVE_WILDCARD_REGEX: &str = r#"
+function sieveWildcardRegex(pattern, caseMap, capture) {
+  const chars = [...pattern];
+  let regex = "^";
+  for (let i = 0; i < chars.length; i++) {
+    switch (chars[i]) {
+      case "*":
+        regex += capture ? "(.*?)" : ".*";
+        break;
+      case "?":
+        regex += capture ? "(.)" : ".";
+        break;
+      case "\\":
+        regex += sieveRegexChar(chars[++i] ?? "\\", caseMap);
+        break;
+      default:
+        regex += sieveRegexChar(chars[i], caseMap);
+    }
+  }
+  return new RegExp(`${regex}$`, "su");
+}
+"#;
+
+/// Whether `comparator` folds ASCII letters in a `match_type` match,
+/// failing on the comparators it doesn't support.
+pub(crate) fn regex_case_map(
+    comparator: &sieve_grammar::Comparator,
+    match_type: &str,
+) -> Result<bool, BoxError> {
+    match comparator {
+        sieve_grammar::Comparator::Octet => Ok(false),
+        sieve_grammar::Comparator::AsciiCaseMap => Ok(true),
+        e => Err(format!("comparator {e:?} doesn't support {match_type}").into()),
+    }
+}
+
+/// Translates a Sieve wildcard pattern (RFC 5228, section 2.7.1) into an
+/// anchored JavaScript regex literal.
+///
+/// With `capture`, every wildcard becomes a group so that its match can be
+/// stored in the match variables. `*` matches as little as possible, like in
+/// sieve-rs. The `u` flag makes `?` match a whole character rather than a
+/// UTF-16 code unit.
+pub(crate) fn sieve_to_js_regex(
+    pattern: &str,
+    comparator: &sieve_grammar::Comparator,
+    capture: bool,
+) -> Result<String, BoxError> {
+    let case_map = regex_case_map(comparator, ":matches")?;
+
+    let mut regex = String::from("/^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if capture => regex.push_str("(.*?)"),
+            '?' if capture => regex.push_str("(.)"),
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            // A backslash quotes the next character, a trailing one is
+            // matched literally.
+            '\\' => push_js_regex_char(&mut regex, chars.next().unwrap_or('\\'), case_map),
+            c => push_js_regex_char(&mut regex, c, case_map),
+        }
+    }
+    regex.push_str("$/su");
+
+    Ok(regex)
+}
+
+/// Generates the regex of a wildcard pattern with variables, their values are
+/// expanded before the wildcards are read (RFC 5229, section 3).
+pub(crate) fn generate_wildcard_regex(
+    ctx: &mut CodeGen,
+    comparator: &sieve_grammar::Comparator,
+    key: &sieve::compiler::Value,
+    capture: bool,
+) -> Result<(), BoxError> {
+    let case_map = regex_case_map(comparator, ":matches")?;
+
+    ctx.add_helper(SIEVE_REGEX_CHAR);
+    ctx.add_helper(SIEVE_WILDCARD_REGEX);
+
+    ctx.buffer.write("sieveWildcardRegex(");
+    generate_value(ctx, key)?;
+    ctx.buffer.write(&format!(", {case_map}, {capture})"));
+
+    Ok(())
+}
+
+/// Pushes `c` so that it only matches itself in a JavaScript regex literal.
+/// With `case_map`, ASCII letters match both cases, the `i` flag would also
+/// fold non-ASCII letters.
+pub(crate) fn push_js_regex_char(regex: &mut String, c: char, case_map: bool) {
+    match c {
+        c if case_map && c.is_ascii_alphabetic() => {
+            regex.push('[');
+            regex.push(c.to_ascii_lowercase());
+            regex.push(c.to_ascii_uppercase());
+            regex.push(']');
+        }
+        '\\' | '^' | '$' | '.' | '|' | '?' | '*' | '+' | '(' | ')' | '[' | ']' | '{' | '}'
+        | '/' => {
+            regex.push('\\');
+            regex.push(c);
+        }
+        c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+            regex.push_str(&format!("\\u{:04x}", c as u32));
+        }
+        c => regex.push(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_is_contains() {
+        let key = sieve::compiler::Value::Text(Arc::new("Ab".to_owned()));
+        let test_cases = &[
+            (
+                sieve_grammar::Comparator::Octet,
+                r#"sieveIs(value, "Ab", "i;octet")"#,
+                r#"sieveContains(value, "Ab", "i;octet")"#,
+            ),
+            (
+                sieve_grammar::Comparator::AsciiCaseMap,
+                r#"sieveIs(value, "Ab", "i;ascii-casemap")"#,
+                r#"sieveContains(value, "Ab", "i;ascii-casemap")"#,
+            ),
+        ];
+
+        for (comparator, expected_is, expected_contains) in test_cases {
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+            generate_is(&mut ctx, comparator, "value", &key).unwrap();
+            assert_eq!(&ctx.buffer.to_string(), expected_is);
+
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+            generate_contains(&mut ctx, comparator, "value", &key).unwrap();
+            assert_eq!(&ctx.buffer.to_string(), expected_contains);
+        }
+
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+        generate_is(
+            &mut ctx,
+            &sieve_grammar::Comparator::AsciiNumeric,
+            "value",
+            &key,
+        )
+        .unwrap();
+        assert_eq!(
+            ctx.buffer.to_string(),
+            r#"sieveIs(value, "Ab", "i;ascii-numeric")"#
+        );
+        generate_contains(
+            &mut ctx,
+            &sieve_grammar::Comparator::AsciiNumeric,
+            "value",
+            &key,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_generate_comparators() {
+        let test_cases = &[
+            (
+                r#"if header :is :comparator "i;octet" "x-a" "Yes" { stop; }"#,
+                r#"sieveHeaderValues(parsedMessage, ["x-a",]).some((value) => sieveIs(value, "Yes", "i;octet"))"#,
+            ),
+            (
+                r#"require ["variables", "comparator-i;ascii-numeric"]; if string :is :comparator "i;ascii-numeric" "007" "7" { stop; }"#,
+                r#"["007",].some((value) => sieveIs(value, "7", "i;ascii-numeric"))"#,
+            ),
+            (
+                r#"if header :is :comparator "i;octet" "x-a" ["1", "1.0"] { stop; }"#,
+                r#".some((value) => (sieveIs(value, "1", "i;octet") || sieveIs(value, "1.0", "i;octet")))"#,
+            ),
+            (
+                r#"require "body"; if body :comparator "i;octet" :contains "MAKE MONEY" { stop; }"#,
+                r#"sieveTextBody(parsedMessage).some((value) => sieveContains(value, "MAKE MONEY", "i;octet"))"#,
+            ),
+            (
+                r#"if address :matches :domain "from" "*.Example.com" { stop; }"#,
+                r#".some((value) => /^.*\.[eE][xX][aA][mM][pP][lL][eE]\.[cC][oO][mM]$/su.test(value))"#,
+            ),
+            (
+                r#"require "envelope"; if envelope :contains "to" "Sales" { stop; }"#,
+                r#".some((value) => sieveContains(value, "Sales", "i;ascii-casemap"))"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+        }
+
+        let script = sieve::Compiler::new()
+            .compile(br#"require "comparator-i;ascii-numeric"; if header :contains :comparator "i;ascii-numeric" "x-a" "1" { stop; }"#)
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+        ctx.generate_js().unwrap_err();
+    }
+
+    #[test]
+    fn test_sieve_to_js_regex() {
+        let test_cases = &[
+            ("*", sieve_grammar::Comparator::AsciiCaseMap, r"/^.*$/su"),
+            (
+                "foo*",
+                sieve_grammar::Comparator::AsciiCaseMap,
+                r"/^[fF][oO][oO].*$/su",
+            ),
+            ("*foo", sieve_grammar::Comparator::Octet, r"/^.*foo$/su"),
+            ("a?c", sieve_grammar::Comparator::Octet, r"/^a.c$/su"),
+            (
+                "[*] *",
+                sieve_grammar::Comparator::AsciiCaseMap,
+                r"/^\[.*\] .*$/su",
+            ),
+            (
+                "a.b+c(d)|e^f$g{1}/h",
+                sieve_grammar::Comparator::Octet,
+                r"/^a\.b\+c\(d\)\|e\^f\$g\{1\}\/h$/su",
+            ),
+            (r"\*\?", sieve_grammar::Comparator::Octet, r"/^\*\?$/su"),
+            (r"a\\b", sieve_grammar::Comparator::Octet, r"/^a\\b$/su"),
+            (r"\a", sieve_grammar::Comparator::Octet, r"/^a$/su"),
+            (r"a\", sieve_grammar::Comparator::Octet, r"/^a\\$/su"),
+            ("a\nb", sieve_grammar::Comparator::Octet, r"/^a\u000ab$/su"),
+            ("?-😀", sieve_grammar::Comparator::Octet, r"/^.-😀$/su"),
+        ];
+
+        let capture_test_cases = &[
+            (
+                "Re: *",
+                sieve_grammar::Comparator::AsciiCaseMap,
+                r"/^[rR][eE]: (.*?)$/su",
+            ),
+            (
+                "É*",
+                sieve_grammar::Comparator::AsciiCaseMap,
+                r"/^É(.*?)$/su",
+            ),
+            ("*", sieve_grammar::Comparator::AsciiCaseMap, r"/^(.*?)$/su"),
+            (
+                "[*] ?*",
+                sieve_grammar::Comparator::Octet,
+                r"/^\[(.*?)\] (.)(.*?)$/su",
+            ),
+            (r"\*?", sieve_grammar::Comparator::Octet, r"/^\*(.)$/su"),
+        ];
+
+        for (pattern, comparator, expected) in capture_test_cases {
+            assert_eq!(
+                &sieve_to_js_regex(pattern, comparator, true).unwrap(),
+                expected
+            );
+        }
+
+        for (pattern, comparator, expected) in test_cases {
+            assert_eq!(
+                &sieve_to_js_regex(pattern, comparator, false).unwrap(),
+                expected
+            );
+        }
+
+        sieve_to_js_regex("*", &sieve_grammar::Comparator::AsciiNumeric, false).unwrap_err();
+    }
+}
@@ -0,0 +1,96 @@
+use crate::codegen::js::address::generate_address_match;
+use crate::codegen::js::test::generate_negatable;
+use crate::codegen::js::{mirror, BoxError, CodeGen};
+
+/// The Email Workers message only exposes the SMTP `MAIL FROM` and
+/// `RCPT TO`, other envelope parts (RFC 6009) aren't available.
+pub(crate) fn generate_test_envelope(
+    ctx: &mut CodeGen,
+    node: &mirror::TestEnvelope,
+) -> Result<(), BoxError> {
+    if node.zone.is_some() {
+        return Err("unsupported :zone in envelope test".into());
+    }
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_address_match(
+            ctx,
+            &node.address_part,
+            &node.match_type,
+            &node.comparator,
+            &node.key_list,
+            |ctx| {
+                ctx.buffer.write("[");
+                for envelope in &node.envelope_list {
+                    match envelope {
+                        sieve::Envelope::From => ctx.buffer.write("{ address: message.from },"),
+                        sieve::Envelope::To => ctx.buffer.write("{ address: message.to },"),
+                        e => return Err(format!("unsupported envelope part: {e:?}").into()),
+                    }
+                }
+                ctx.buffer.write("]");
+                Ok(())
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use sieve::compiler::grammar::instruction::Instruction;
+
+    fn generate_script_envelope(script: &str) -> Result<String, BoxError> {
+        let script = sieve::Compiler::new().compile(script.as_bytes()).unwrap();
+        let test = script
+            .instructions
+            .iter()
+            .find_map(|instr| match instr {
+                Instruction::Test(test) => Some(test),
+                _ => None,
+            })
+            .unwrap();
+        let mirror::Test::Envelope(node) = mirror::Test::try_from(test)? else {
+            unreachable!();
+        };
+
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+        generate_test_envelope(&mut ctx, &node)?;
+        Ok(ctx.buffer.to_string())
+    }
+
+    #[test]
+    fn test_generate_test_envelope() {
+        let test_cases = &[
+            (
+                r#"require "envelope"; if envelope :is "to" "a@example.com" { stop; }"#,
+                r#"[{ address: message.to },].map((address) => sieveAddressPart(address, "all")).some((value) => sieveIs(value, "a@example.com", "i;ascii-casemap"))"#,
+            ),
+            (
+                r#"require "envelope"; if envelope :domain :is ["from", "to"] ["example.com", "example.org"] { stop; }"#,
+                r#"[{ address: message.from },{ address: message.to },].map((address) => sieveAddressPart(address, "domain")).some((value) => (sieveIs(value, "example.com", "i;ascii-casemap") || sieveIs(value, "example.org", "i;ascii-casemap")))"#,
+            ),
+            (
+                r#"require "envelope"; if not envelope :localpart :contains "from" "bounce" { stop; }"#,
+                r#"!([{ address: message.from },].map((address) => sieveAddressPart(address, "localpart")).some((value) => sieveContains(value, "bounce", "i;ascii-casemap")))"#,
+            ),
+            (
+                r#"require ["envelope", "subaddress"]; if envelope :detail "to" "sales" { stop; }"#,
+                r#"[{ address: message.to },].map((address) => sieveAddressPart(address, "detail")).filter((value) => value !== null).some((value) => sieveIs(value, "sales", "i;ascii-casemap"))"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(&generate_script_envelope(input).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_generate_test_envelope_unsupported_part() {
+        generate_script_envelope(
+            r#"require ["envelope", "envelope-dsn"]; if envelope "orcpt" "a@example.com" { stop; }"#,
+        )
+        .unwrap_err();
+    }
+}
@@ -0,0 +1,61 @@
+use crate::codegen::js::test::generate_negatable;
+use crate::codegen::js::{generate_value, mirror, BoxError, CodeGen};
+
+/// All the headers must exist (RFC 5228, section 5.5). Header names are
+/// lowercased by PostalMime.
+pub(crate) fn generate_test_exists(
+    ctx: &mut CodeGen,
+    node: &mirror::TestExists,
+) -> Result<(), BoxError> {
+    if node.mime_anychild {
+        return Err("unsupported :anychild in exists test".into());
+    }
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        ctx.buffer.write("[");
+        for name in &node.header_names {
+            generate_value(ctx, name)?;
+            ctx.buffer.write(",");
+        }
+        ctx.buffer.write("].every((name) => parsedMessage.headers.some((header) => header.key === name.toLowerCase()))");
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_test_exists() {
+        let test_cases = &[
+            (
+                vec!["List-Id"],
+                false,
+                r#"["List-Id",].every((name) => parsedMessage.headers.some((header) => header.key === name.toLowerCase()))"#,
+            ),
+            (
+                vec!["From", "X-Spam"],
+                true,
+                r#"!(["From","X-Spam",].every((name) => parsedMessage.headers.some((header) => header.key === name.toLowerCase())))"#,
+            ),
+        ];
+
+        for (header_names, is_not, expected) in test_cases {
+            let node = mirror::TestExists {
+                header_names: header_names
+                    .iter()
+                    .map(|h| sieve::compiler::Value::Text(Arc::new(h.to_string())))
+                    .collect(),
+                mime_anychild: false,
+                is_not: *is_not,
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_test_exists(&mut ctx, &node).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+        }
+    }
+}
@@ -1,28 +1,86 @@
-use crate::codegen::js::{BoxError, CodeGen};
+use crate::codegen::js::{generate_value, keep, BoxError, CodeGen};
 use sieve::compiler::grammar as sieve_grammar;
 
 pub(crate) fn generate_fileinto(
     ctx: &mut CodeGen,
     node: &sieve_grammar::actions::action_fileinto::FileInto,
 ) -> Result<(), BoxError> {
-    let dest = match &node.folder {
-        sieve::compiler::Value::Text(s) => s,
+    // Scoped so that a script can file the message more than once.
+    ctx.add_helper(keep::SIEVE_MESSAGE_KEY);
+    ctx.buffer.write("{");
+    ctx.buffer.write("const key = sieveMessageKey(message);");
 
-        e => unreachable!("invalid fileinto destination: {e:?}"),
-    };
+    match &node.folder {
+        sieve::compiler::Value::Text(dest) => {
+            let bucket = dest
+                .strip_prefix("r2://")
+                .ok_or_else(|| format!("invalid fileinto destination: {dest}"))?;
 
-    assert!(dest.starts_with("r2://"));
+            ctx.buffer
+                .write(&format!("await env.{bucket}.put(key, raw);"));
+        }
 
-    let bucket = dest.replace("r2://", "");
-
-    ctx.buffer
-        .write("const key = (parsedMessage.messageId || crypto.randomUUID()) + '.eml';");
-    ctx.buffer
-        .write(&format!("await env.{bucket}.put(key, raw);"));
+        // The destination is only known at runtime.
+        folder => {
+            ctx.buffer.write("const dest = ");
+            generate_value(ctx, folder)?;
+            ctx.buffer.write(";");
+            ctx.buffer.write(
+                "if (!dest.startsWith(\"r2://\")) { throw new Error(`invalid fileinto destination: ${dest}`); }",
+            );
+            ctx.buffer
+                .write("await env[dest.slice(\"r2://\".length)].put(key, raw);");
+        }
+    }
 
     if ctx.opts.debug {
         ctx.buffer.write("console.log('stored email at ', key);");
     }
+    if !node.copy {
+        keep::generate_cancel_implicit_keep(ctx);
+    }
+    ctx.buffer.write("}");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_fileinto() {
+        let test_cases = &[
+            (
+                sieve::compiler::Value::Text(Arc::new("r2://BUCKET".to_owned())),
+                "{const key = sieveMessageKey(message);await env.BUCKET.put(key, raw);implicitKeep = false;}",
+            ),
+            (
+                sieve::compiler::Value::List(vec![
+                    sieve::compiler::Value::Text(Arc::new("r2://".to_owned())),
+                    sieve::compiler::Value::Variable(sieve::compiler::VariableType::Match(1)),
+                ]),
+                r#"{const key = sieveMessageKey(message);const dest = `r2://${variables["match1"] ?? ""}`;if (!dest.startsWith("r2://")) { throw new Error(`invalid fileinto destination: ${dest}`); }await env[dest.slice("r2://".length)].put(key, raw);implicitKeep = false;}"#,
+            ),
+        ];
+
+        for (folder, expected) in test_cases {
+            let node = sieve_grammar::actions::action_fileinto::FileInto {
+                copy: false,
+                create: false,
+                folder: folder.clone(),
+                flags: vec![],
+                mailbox_id: None,
+                special_use: None,
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_fileinto(&mut ctx, &node).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+            // The same key as the one of keep.
+            assert!(ctx.helpers.contains(&keep::SIEVE_MESSAGE_KEY));
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::codegen::js::test::{generate_negatable, generate_values_match};
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+use sieve::compiler::grammar as sieve_grammar;
+
+/// Returns the values of every occurrence of the given headers, in the order
+/// of the message. The subject is the only header PostalMime exposes decoded
+/// (RFC 2047).
+const SIEVE_HEADER_VALUES: &str = r#"
+function sieveHeaderValues(parsedMessage, headerNames) {
+  const names = headerNames.map((name) => name.toLowerCase());
+  return parsedMessage.headers
+    .filter((header) => names.includes(header.key))
+    .map((header) =>
+      header.key === "subject" && parsedMessage.subject !== undefined
+        ? parsedMessage.subject
+        : header.value,
+    );
+}
+"#;
+
+/// Any occurrence of any of the headers against any of the keys (RFC 5228,
+/// section 5.7). A missing header has no value and never matches.
+pub(crate) fn generate_test_header(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::tests::test_header::TestHeader,
+) -> Result<(), BoxError> {
+    if node.index.is_some() {
+        return Err("unsupported :index in header test".into());
+    }
+    if node.mime_anychild || node.mime_opts != sieve_grammar::actions::action_mime::MimeOpts::None {
+        return Err("unsupported :mime in header test".into());
+    }
+
+    ctx.add_helper(SIEVE_HEADER_VALUES);
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_values_match(
+            ctx,
+            &node.match_type,
+            &node.comparator,
+            &node.key_list,
+            |ctx| {
+                ctx.buffer.write("sieveHeaderValues(parsedMessage, [");
+                for header in &node.header_list {
+                    generate_value(ctx, header)?;
+                    ctx.buffer.write(",");
+                }
+                ctx.buffer.write("])");
+                Ok(())
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_test_header() {
+        let test_cases = &[
+            (
+                vec!["X-Spam"],
+                vec!["yes"],
+                sieve_grammar::MatchType::Is,
+                r#"sieveHeaderValues(parsedMessage, ["X-Spam",]).some((value) => sieveIs(value, "yes", "i;ascii-casemap"))"#,
+            ),
+            (
+                vec!["From", "Sender"],
+                vec!["alice", "bob"],
+                sieve_grammar::MatchType::Contains,
+                r#"sieveHeaderValues(parsedMessage, ["From","Sender",]).some((value) => (sieveContains(value, "alice", "i;ascii-casemap") || sieveContains(value, "bob", "i;ascii-casemap")))"#,
+            ),
+            (
+                vec!["Subject"],
+                vec!["[*]*"],
+                sieve_grammar::MatchType::Matches(0),
+                r#"sieveHeaderValues(parsedMessage, ["Subject",]).some((value) => /^\[.*\].*$/su.test(value))"#,
+            ),
+        ];
+
+        for (headers, keys, match_type, expected) in test_cases {
+            let test = sieve_grammar::tests::test_header::TestHeader {
+                header_list: headers
+                    .iter()
+                    .map(|h| sieve::compiler::Value::Text(Arc::new(h.to_string())))
+                    .collect(),
+                key_list: keys
+                    .iter()
+                    .map(|k| sieve::compiler::Value::Text(Arc::new(k.to_string())))
+                    .collect(),
+                match_type: *match_type,
+                comparator: sieve_grammar::Comparator::AsciiCaseMap,
+                mime_opts: sieve_grammar::actions::action_mime::MimeOpts::None,
+                index: None,
+                mime_anychild: false,
+                is_not: false,
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_test_header(&mut ctx, &test).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+            assert_eq!(ctx.helpers[0], SIEVE_HEADER_VALUES);
+        }
+    }
+
+    #[test]
+    fn test_generate_test_header_unsupported() {
+        let test_cases = &[
+            r#"require "index"; if header :index 2 "received" "x" { stop; }"#,
+            r#"require ["mime", "foreverypart"]; if header :mime :type "content-type" "text" { stop; }"#,
+        ];
+
+        for input in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+
+            ctx.generate_js().unwrap_err();
+        }
+    }
+}
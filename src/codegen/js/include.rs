@@ -0,0 +1,374 @@
+//! Include (RFC 6609).
+//!
+//! Included scripts are read at compile time and compiled into functions of
+//! the same module, declared inside `run`. They share the `globals`, the
+//! implicit keep and the actions of the execution, their local and match
+//! variables are their own.
+
+use crate::codegen::buffer::escape_js_string;
+use crate::codegen::js::mirror::{self, IncludeLocation};
+use crate::codegen::js::{generate_instr, BoxError, CodeGen};
+use crate::codegen::GenerateOpts;
+use sieve::compiler::grammar::instruction::Instruction;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Runs an included script, unless it's included `:once` and already ran.
+/// Returns whether the script stopped the execution.
+const SIEVE_INCLUDE: &str = r#"
+async function sieveInclude(included, name, once, script) {
+  if (once && included.has(name)) {
+    return false;
+  }
+  included.add(name);
+  return script();
+}
+"#;
+
+pub(crate) struct IncludedScript {
+    location: IncludeLocation,
+    name: String,
+    pub(crate) script: sieve::Sieve,
+}
+
+impl IncludedScript {
+    /// Identifies the script in the `included` set of the execution.
+    fn key(&self) -> String {
+        match self.location {
+            IncludeLocation::Personal => format!("personal:{}", self.name),
+            IncludeLocation::Global => format!("global:{}", self.name),
+        }
+    }
+}
+
+/// Reads and compiles every script included by `instructions`, and the ones
+/// they include in turn.
+pub(crate) fn load_includes(
+    opts: &GenerateOpts,
+    instructions: &[Instruction],
+) -> Result<Vec<IncludedScript>, BoxError> {
+    let mut includes = vec![];
+    load_includes_of(opts, instructions, &mut vec![], &mut includes)?;
+    Ok(includes)
+}
+
+fn load_includes_of(
+    opts: &GenerateOpts,
+    instructions: &[Instruction],
+    stack: &mut Vec<(IncludeLocation, String)>,
+    includes: &mut Vec<IncludedScript>,
+) -> Result<(), BoxError> {
+    for instr in instructions {
+        let Some(node) = mirror::Include::from_instruction(instr)? else {
+            continue;
+        };
+        let name = include_name(&node)?;
+
+        // A script including itself again can only run once (RFC 6609,
+        // section 3.2).
+        if stack
+            .iter()
+            .any(|(location, n)| *location == node.location && *n == name)
+        {
+            if node.once {
+                continue;
+            }
+            return Err(format!("include loop on script {name:?}, use :once").into());
+        }
+        if includes
+            .iter()
+            .any(|included| included.location == node.location && included.name == name)
+        {
+            continue;
+        }
+
+        let path = script_path(opts, node.location, &name)?;
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && node.optional => continue,
+            Err(err) => {
+                return Err(
+                    format!("failed to read included script {}: {err}", path.display()).into(),
+                )
+            }
+        };
+        let script = sieve::Compiler::new()
+            .compile(&contents)
+            .map_err(|err| format!("failed to parse included script {name:?}: {err}"))?;
+
+        stack.push((node.location, name.clone()));
+        load_includes_of(opts, &script.instructions, stack, includes)?;
+        stack.pop();
+
+        includes.push(IncludedScript {
+            location: node.location,
+            name,
+            script,
+        });
+    }
+
+    Ok(())
+}
+
+fn include_name(node: &mirror::Include) -> Result<String, BoxError> {
+    match &node.value {
+        sieve::compiler::Value::Text(name) if !name.contains(['/', '\\']) => Ok(name.to_string()),
+        e => Err(format!("unsupported included script name: {e:?}").into()),
+    }
+}
+
+/// Personal scripts are looked up in the include directory, global ones in
+/// the global include directory, as `NAME.sieve`.
+fn script_path(
+    opts: &GenerateOpts,
+    location: IncludeLocation,
+    name: &str,
+) -> Result<PathBuf, BoxError> {
+    let dir = match location {
+        IncludeLocation::Personal => opts
+            .include_dir
+            .as_ref()
+            .ok_or("include needs a directory of scripts, see --include-dir")?,
+        IncludeLocation::Global => opts
+            .global_include_dir
+            .as_ref()
+            .ok_or("include :global needs a directory of scripts, see --global-include-dir")?,
+    };
+
+    Ok(dir.join(format!("{name}.sieve")))
+}
+
+/// The position of the script of `node` in `includes`, if it was loaded.
+pub(crate) fn find_included(
+    includes: &[IncludedScript],
+    node: &mirror::Include,
+) -> Result<Option<usize>, BoxError> {
+    let name = include_name(node)?;
+
+    Ok(includes
+        .iter()
+        .position(|included| included.location == node.location && included.name == name))
+}
+
+pub(crate) fn generate_include(ctx: &mut CodeGen, node: &mirror::Include) -> Result<(), BoxError> {
+    let name = include_name(node)?;
+
+    let Some(idx) = find_included(&ctx.includes, node)? else {
+        // A missing :optional script is ignored.
+        if node.optional {
+            return Ok(());
+        }
+        return Err(format!("included script {name:?} not found").into());
+    };
+
+    if ctx.opts.debug {
+        ctx.buffer.write(&format!(
+            "console.log(\"include {}\");",
+            escape_js_string(&name, '"')
+        ));
+    }
+
+    ctx.add_helper(SIEVE_INCLUDE);
+
+    let key = ctx.includes[idx].key();
+    ctx.buffer.write(&format!(
+        "if (await sieveInclude(included, \"{}\", {}, sieveScript{idx})) {{",
+        escape_js_string(&key, '"'),
+        node.once
+    ));
+    generate_stop(ctx);
+    ctx.buffer.write("}");
+
+    Ok(())
+}
+
+/// Stops the execution, an included script tells the script including it to
+/// stop as well.
+pub(crate) fn generate_stop(ctx: &mut CodeGen) {
+    if ctx.in_include {
+        ctx.buffer.write("return true;");
+    } else {
+        ctx.buffer.write("return;");
+    }
+}
+
+/// Ends the current script, like `stop` in the main script (RFC 6609,
+/// section 3.3).
+pub(crate) fn generate_return(ctx: &mut CodeGen) {
+    if ctx.in_include {
+        ctx.buffer.write("return false;");
+    } else {
+        ctx.buffer.write("return;");
+    }
+}
+
+/// Generates the functions of the included scripts.
+pub(crate) fn generate_included_scripts(ctx: &mut CodeGen) -> Result<(), BoxError> {
+    let includes = Rc::clone(&ctx.includes);
+
+    for (idx, included) in includes.iter().enumerate() {
+        let mut script_ctx = CodeGen::new(ctx.opts.clone(), &included.script.instructions);
+        script_ctx.includes = Rc::clone(&includes);
+        script_ctx.in_include = true;
+
+        script_ctx.buffer.newline();
+        script_ctx
+            .buffer
+            .write(&format!("async function sieveScript{idx}() {{"));
+        while script_ctx.cursor < script_ctx.instructions.len() {
+            let instr = script_ctx.eat();
+            generate_instr(&mut script_ctx, instr)?;
+        }
+        script_ctx.buffer.write("}");
+
+        ctx.buffer.write(&script_ctx.buffer.to_string());
+        for helper in script_ctx.helpers {
+            ctx.add_helper(helper);
+        }
+        for import in script_ctx.imports {
+            ctx.add_import(import);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `scripts` into a directory of their own, removed by the test.
+    fn include_dir(test: &str, scripts: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sieve2workers-{}-{test}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in scripts {
+            std::fs::write(dir.join(format!("{name}.sieve")), contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_generate_include_globals() {
+        let dir = include_dir(
+            "test_generate_include_globals",
+            &[(
+                "forward",
+                r#"require ["include", "variables"]; global "target"; if string :is "${target}" "" { return; } redirect "${target}"; stop;"#,
+            )],
+        );
+        let script = sieve::Compiler::new()
+            .compile(br#"require ["include", "variables"]; global "target"; set "target" "a@example.com"; include "forward"; include :once :optional "missing"; discard;"#)
+            .unwrap();
+        let opts = GenerateOpts {
+            include_dir: Some(dir.clone()),
+            ..GenerateOpts::default()
+        };
+        let mut ctx = CodeGen::new(opts, &script.instructions);
+        let js = ctx.generate_js().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(
+            js.contains(r#"globals["target"] = "a@example.com";"#),
+            "{js}"
+        );
+        assert!(js.contains(r#"const included = new Set();"#), "{js}");
+        assert!(
+            js.contains(r#"if (await sieveInclude(included, "personal:forward", false, sieveScript0)) {return;}// discard the email"#),
+            "{js}"
+        );
+        assert!(js.contains(r#"async function sieveScript0() {"#), "{js}");
+        assert!(js.contains(r#"if ([`${globals["target"] ?? ""}`,].some((value) => sieveIs(value, "", "i;ascii-casemap"))) {return false;}"#), "{js}");
+        assert!(js.contains(r#"await sieveRedirect(message, redirected, `${globals["target"] ?? ""}`, extraHeaders);implicitKeep = false;return true;}"#), "{js}");
+    }
+
+    #[test]
+    fn test_check_reject_compatibility_include() {
+        let dir = include_dir(
+            "test_check_reject_compatibility_include",
+            &[
+                ("reject", r#"require "reject"; reject "no";"#),
+                ("forward", r#"redirect "a@example.com";"#),
+                (
+                    "both",
+                    r#"require "include"; include "reject"; include "forward";"#,
+                ),
+                (
+                    "reject-return",
+                    r#"require ["reject", "include"]; if size :over 1M { reject "too big"; return; } redirect "a@example.com";"#,
+                ),
+            ],
+        );
+        let test_cases = &[
+            (
+                r#"require "include"; include "reject"; include "forward";"#,
+                false,
+            ),
+            (
+                r#"require "include"; if size :over 1M { include "reject"; } else { include "forward"; }"#,
+                true,
+            ),
+            (r#"require "include"; include "reject"; keep;"#, false),
+            (
+                r#"require ["include", "reject"]; include "forward"; reject "no";"#,
+                false,
+            ),
+            (r#"require "include"; include "both";"#, false),
+            (
+                r#"require "include"; include "reject"; include "reject";"#,
+                false,
+            ),
+            (
+                r#"require "include"; include "reject"; include :once "reject";"#,
+                true,
+            ),
+            (r#"require "include"; include "reject-return";"#, true),
+        ];
+
+        for (input, compatible) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                include_dir: Some(dir.clone()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+
+            assert_eq!(ctx.generate_js().is_ok(), *compatible, "{input}");
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_generate_include_invalid() {
+        let dir = include_dir(
+            "test_generate_include_invalid",
+            &[
+                ("loop", r#"require "include"; include "loop";"#),
+                (
+                    "loop-once",
+                    r#"require "include"; include :once "loop-once";"#,
+                ),
+            ],
+        );
+        let test_cases = &[
+            (r#"require "include"; include "loop";"#, false),
+            (r#"require "include"; include "loop-once";"#, true),
+            (r#"require "include"; include "missing";"#, false),
+            (r#"require "include"; include :optional "missing";"#, true),
+            (r#"require "include"; include :global "loop";"#, false),
+        ];
+
+        for (input, ok) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                include_dir: Some(dir.clone()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+
+            assert_eq!(ctx.generate_js().is_ok(), *ok, "{input}");
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Keep (RFC 5228, section 4.3) and implicit keep (section 2.10.2).
+//!
+//! Email Workers have no mailbox to keep the message in, it goes to a
+//! destination instead: an address to forward it to or an R2 bucket
+//! (`r2://BUCKET`). The `SIEVE_KEEP` environment variable of the Worker takes
+//! precedence over the destination given at compile time.
+
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+
+/// The key of the message in R2, from its Message-ID. It's the same for every
+/// action storing the message.
+pub(crate) const SIEVE_MESSAGE_KEY: &str = r#"
+const sieveMessageKeys = new WeakMap();
+
+function sieveMessageKey(message) {
+  if (!sieveMessageKeys.has(message)) {
+    const messageId = (message.headers.get("Message-ID") ?? "").trim().replace(/^<(.*)>$/, "$1");
+    sieveMessageKeys.set(message, (messageId || crypto.randomUUID()) + ".eml");
+  }
+  return sieveMessageKeys.get(message);
+}
+"#;
+
+/// The imap4flags (RFC 5232) of the message are only kept in R2, as custom
+/// metadata. A forwarded message has none.
+const SIEVE_KEEP: &str = r#"
+async function sieveKeep(message, env, extraHeaders, destination, raw, flags = []) {
+  if (!destination) {
+    console.warn("no keep destination, the message is dropped");
+    return;
+  }
+  if (destination.startsWith("r2://")) {
+    const names = [...new Set(flags.flatMap((flag) => flag.split(" ")).filter(Boolean))];
+    await env[destination.slice("r2://".length)].put(
+      sieveMessageKey(message),
+      raw ?? (await new Response(message.raw).arrayBuffer()),
+      names.length > 0 ? { customMetadata: { flags: names.join(" ") } } : {},
+    );
+  } else {
+    await message.forward(destination, extraHeaders);
+  }
+}
+"#;
+
+/// Generates the start of the script, the implicit keep happens when it
+/// returns, including after a `stop`. A failed rule keeps the message even if
+/// an action cancelled the implicit keep (RFC 5228, section 2.10.6).
+pub(crate) fn generate_implicit_keep_start(ctx: &mut CodeGen) {
+    ctx.buffer.write("let implicitKeep = true;");
+    ctx.buffer.write("let kept = false;");
+    ctx.buffer.write("let failed = false;");
+    ctx.buffer.newline();
+    ctx.buffer.write("try {");
+}
+
+pub(crate) fn generate_implicit_keep_end(ctx: &mut CodeGen) -> Result<(), BoxError> {
+    ctx.buffer.newline();
+    ctx.buffer.write("} catch (err) {");
+    ctx.buffer.write("failed = true;");
+    ctx.buffer.write("throw err;");
+    ctx.buffer.write("} finally {");
+    ctx.buffer.write("if ((implicitKeep || failed) && !kept) {");
+    generate_deliver(ctx, &[])?;
+    ctx.buffer.write("}");
+    ctx.buffer.write("}");
+
+    Ok(())
+}
+
+/// An explicit keep, the message is only kept once whatever the number of
+/// keeps.
+pub(crate) fn generate_keep(
+    ctx: &mut CodeGen,
+    node: &sieve::compiler::grammar::actions::action_keep::Keep,
+) -> Result<(), BoxError> {
+    if ctx.opts.debug {
+        ctx.buffer.write("console.log(\"keep\");");
+    }
+
+    ctx.buffer.write("if (!kept) {");
+    ctx.buffer.write("kept = true;");
+    generate_deliver(ctx, &node.flags)?;
+    ctx.buffer.write("}");
+
+    Ok(())
+}
+
+fn generate_deliver(ctx: &mut CodeGen, flags: &[sieve::compiler::Value]) -> Result<(), BoxError> {
+    ctx.add_helper(SIEVE_MESSAGE_KEY);
+    ctx.add_helper(SIEVE_KEEP);
+
+    ctx.buffer
+        .write("await sieveKeep(message, env, extraHeaders, env.SIEVE_KEEP ?? ");
+    match &ctx.opts.keep {
+        Some(destination) => ctx.buffer.write_quoted(destination),
+        None => ctx.buffer.write("undefined"),
+    }
+    // The message is only buffered when the script reads it.
+    if ctx.needs_message_content() {
+        ctx.buffer.write(", raw");
+    } else {
+        ctx.buffer.write(", undefined");
+    }
+    if !flags.is_empty() {
+        ctx.buffer.write(", [");
+        for flag in flags {
+            generate_value(ctx, flag)?;
+            ctx.buffer.write(",");
+        }
+        ctx.buffer.write("]");
+    }
+    ctx.buffer.write(");");
+
+    Ok(())
+}
+
+/// Cancels the implicit keep, for every action delivering the message
+/// somewhere else or dropping it.
+pub(crate) fn generate_cancel_implicit_keep(ctx: &mut CodeGen) {
+    ctx.buffer.write("implicitKeep = false;");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_implicit_keep() {
+        let test_cases = &[
+            ("discard;", false),
+            ("stop;", true),
+            ("require \"fileinto\"; fileinto \"r2://BUCKET\";", false),
+            (
+                "require [\"fileinto\", \"copy\"]; fileinto :copy \"r2://BUCKET\";",
+                true,
+            ),
+            ("redirect \"a@example.com\";", false),
+            ("require \"copy\"; redirect :copy \"a@example.com\";", true),
+            ("require \"reject\"; reject \"no\";", false),
+        ];
+
+        for (input, kept) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                keep: Some("inbox@example.com".to_owned()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(
+                r#"} catch (err) {failed = true;throw err;} finally {if ((implicitKeep || failed) && !kept) {await sieveKeep(message, env, extraHeaders, env.SIEVE_KEEP ?? "inbox@example.com", "#
+            ), "{js}");
+            assert_eq!(!js.contains("implicitKeep = false;"), *kept, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_generate_implicit_keep_failed() {
+        let script = sieve::Compiler::new()
+            .compile(br#"discard; if header :is "X-A" "b" { stop; }"#)
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+        let js = ctx.generate_js().unwrap();
+
+        assert!(
+            js.contains("failed and has been skipped', err);failed = true;}"),
+            "{js}"
+        );
+        assert!(
+            js.contains("} catch (err) {failed = true;throw err;} finally {if ((implicitKeep || failed) && !kept) {"),
+            "{js}"
+        );
+    }
+
+    #[test]
+    fn test_generate_keep() {
+        let test_cases = &[
+            (
+                "keep;",
+                r#"if (!kept) {kept = true;await sieveKeep(message, env, extraHeaders, env.SIEVE_KEEP ?? "r2://INBOX", undefined);}"#,
+            ),
+            (
+                r#"require "imap4flags"; keep :flags ["\\Seen", "\\Flagged $Work"];"#,
+                r#"if (!kept) {kept = true;await sieveKeep(message, env, extraHeaders, env.SIEVE_KEEP ?? "r2://INBOX", undefined, ["\\Seen","\\Flagged $Work",]);}"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                keep: Some("r2://INBOX".to_owned()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+        }
+    }
+}
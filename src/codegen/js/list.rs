@@ -0,0 +1,130 @@
+//! External lists (RFC 6134).
+
+use crate::codegen::buffer::escape_js_string;
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+use crate::codegen::ListSource;
+
+/// Returns the members of every list, a missing list has none.
+const SIEVE_LIST_MEMBERS: &str = r#"
+async function sieveListMembers(source, env, names) {
+  const lists = await Promise.all(
+    names.map(async (name) => {
+      if (source.type === "kv") {
+        return env[source.binding].get(name, "json");
+      }
+      const lists = typeof env[source.binding] === "string" ? JSON.parse(env[source.binding]) : env[source.binding];
+      return lists?.[name];
+    }),
+  );
+  return lists.flatMap((members) => (Array.isArray(members) ? members.map(String) : []));
+}
+"#;
+
+/// Addresses are compared case-insensitively, the comparator doesn't apply to
+/// lists.
+const SIEVE_IN_LIST: &str = r#"
+function sieveInList(values, members) {
+  const set = new Set(members.map((member) => member.toLowerCase()));
+  return values.some((value) => set.has(String(value).toLowerCase()));
+}
+"#;
+
+/// Generates the `:list` match of the values of the JavaScript array written
+/// by `values` against the lists named by `keys`.
+pub(crate) fn generate_list_match<'a>(
+    ctx: &mut CodeGen<'a>,
+    keys: &[sieve::compiler::Value],
+    values: impl FnOnce(&mut CodeGen<'a>) -> Result<(), BoxError>,
+) -> Result<(), BoxError> {
+    ctx.add_helper(SIEVE_IN_LIST);
+
+    ctx.buffer.write("sieveInList(");
+    values(ctx)?;
+    ctx.buffer.write(", ");
+    generate_list_members(ctx, keys)?;
+    ctx.buffer.write(")");
+
+    Ok(())
+}
+
+/// Generates the lookup of the members of the lists named by `names`.
+pub(crate) fn generate_list_members(
+    ctx: &mut CodeGen,
+    names: &[sieve::compiler::Value],
+) -> Result<(), BoxError> {
+    let (kind, binding) = match &ctx.opts.lists {
+        Some(ListSource::Kv(binding)) => ("kv", binding),
+        Some(ListSource::Json(binding)) => ("json", binding),
+        None => return Err("external lists need a list source, see --lists".into()),
+    };
+    let source = format!(
+        "{{ type: \"{kind}\", binding: \"{}\" }}",
+        escape_js_string(binding, '"')
+    );
+
+    ctx.add_helper(SIEVE_LIST_MEMBERS);
+
+    ctx.buffer
+        .write(&format!("(await sieveListMembers({source}, env, ["));
+    for name in names {
+        generate_value(ctx, name)?;
+        ctx.buffer.write(",");
+    }
+    ctx.buffer.write("]))");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_list() {
+        let test_cases = &[
+            (
+                r#"require ["extlists", "envelope"]; if envelope :list "from" "tag:example.com,2024:blocked" { discard; }"#,
+                ListSource::Kv("LISTS".to_owned()),
+                r#"sieveInList([{ address: message.from },].map((address) => sieveAddressPart(address, "all")), (await sieveListMembers({ type: "kv", binding: "LISTS" }, env, ["tag:example.com,2024:blocked",])))"#,
+            ),
+            (
+                r#"require "extlists"; if not header :list "List-Id" ["a", "b"] { stop; }"#,
+                ListSource::Json("LISTS".to_owned()),
+                r#"!(sieveInList(sieveHeaderValues(parsedMessage, ["List-Id",]), (await sieveListMembers({ type: "json", binding: "LISTS" }, env, ["a","b",]))))"#,
+            ),
+            (
+                r#"require "extlists"; redirect :list "tag:example.com,2024:team-oncall";"#,
+                ListSource::Kv("LISTS".to_owned()),
+                r#"{let sent = false;for (const address of (await sieveListMembers({ type: "kv", binding: "LISTS" }, env, ["tag:example.com,2024:team-oncall",]))) {await sieveRedirect(message, redirected, address, extraHeaders);sent = true;}if (sent) {implicitKeep = false;}}"#,
+            ),
+            (
+                r#"require ["extlists", "copy"]; redirect :copy :list "tag:example.com,2024:team-oncall";"#,
+                ListSource::Json("LISTS".to_owned()),
+                r#"await sieveRedirect(message, redirected, address, extraHeaders);sent = true;}}"#,
+            ),
+        ];
+
+        for (input, source, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                lists: Some(source.clone()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+        }
+    }
+
+    #[test]
+    fn test_generate_list_without_source() {
+        let script = sieve::Compiler::new()
+            .compile(br#"require "extlists"; if header :list "From" "a" { stop; }"#)
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+
+        ctx.generate_js().unwrap_err();
+    }
+}
@@ -0,0 +1,131 @@
+//! sieve-rs keeps some of its grammar nodes crate-private, which means they
+//! can't be used from here at all. Every node is serializable though, so they
+//! are converted through serde into the mirrors below.
+
+use crate::BoxError;
+use serde::Deserialize;
+use sieve::compiler::grammar as sieve_grammar;
+use sieve::compiler::grammar::instruction::Instruction;
+use sieve::compiler::Value;
+
+#[derive(Debug, Deserialize)]
+pub(crate) enum Test {
+    Envelope(TestEnvelope),
+    Size(TestSize),
+    Exists(TestExists),
+    #[serde(rename = "VirusTest")]
+    Virus(TestVirusTest),
+}
+
+impl TryFrom<&sieve_grammar::test::Test> for Test {
+    type Error = BoxError;
+
+    fn try_from(node: &sieve_grammar::test::Test) -> Result<Self, Self::Error> {
+        let value = serde_json::to_value(node)?;
+        serde_json::from_value(value).map_err(|err| format!("test not implemented: {err}").into())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TestEnvelope {
+    pub(crate) envelope_list: Vec<sieve::Envelope>,
+    pub(crate) key_list: Vec<Value>,
+    pub(crate) address_part: sieve_grammar::AddressPart,
+    pub(crate) match_type: sieve_grammar::MatchType,
+    pub(crate) comparator: sieve_grammar::Comparator,
+    pub(crate) zone: Option<i64>,
+    pub(crate) is_not: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TestSize {
+    pub(crate) over: bool,
+    pub(crate) limit: usize,
+    pub(crate) is_not: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TestExists {
+    pub(crate) header_names: Vec<Value>,
+    pub(crate) mime_anychild: bool,
+    pub(crate) is_not: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TestVirusTest {
+    pub(crate) value: Value,
+    pub(crate) match_type: sieve_grammar::MatchType,
+    pub(crate) comparator: sieve_grammar::Comparator,
+    pub(crate) is_not: bool,
+}
+
+/// The relational match types (RFC 5231), the others are public.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub(crate) enum Relational {
+    Value(RelationalMatch),
+    Count(RelationalMatch),
+}
+
+impl TryFrom<&sieve_grammar::MatchType> for Relational {
+    type Error = BoxError;
+
+    fn try_from(node: &sieve_grammar::MatchType) -> Result<Self, Self::Error> {
+        let value = serde_json::to_value(node)?;
+        serde_json::from_value(value).map_err(|err| format!("not a relational match: {err}").into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub(crate) enum RelationalMatch {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+/// Only whether vacation has an :fcc (RFC 8580) matters, its type is private.
+#[derive(Debug, Deserialize)]
+pub(crate) struct VacationFcc {
+    pub(crate) fcc: Option<serde_json::Value>,
+}
+
+impl TryFrom<&sieve_grammar::actions::action_vacation::Vacation> for VacationFcc {
+    type Error = BoxError;
+
+    fn try_from(
+        node: &sieve_grammar::actions::action_vacation::Vacation,
+    ) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_value(serde_json::to_value(node)?)?)
+    }
+}
+
+/// The include action (RFC 6609), its type is private.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Include {
+    pub(crate) location: IncludeLocation,
+    pub(crate) once: bool,
+    pub(crate) optional: bool,
+    pub(crate) value: Value,
+}
+
+impl Include {
+    /// The include of `node`, `None` for any other instruction. Even the
+    /// variant of an include can't be matched.
+    pub(crate) fn from_instruction(node: &Instruction) -> Result<Option<Self>, BoxError> {
+        match serde_json::to_value(node)? {
+            serde_json::Value::Object(mut node) => match node.remove("Include") {
+                Some(include) => Ok(Some(serde_json::from_value(include)?)),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub(crate) enum IncludeLocation {
+    Personal,
+    Global,
+}
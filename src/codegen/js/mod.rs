@@ -2,21 +2,45 @@ use super::{buffer, GenerateOpts};
 use crate::BoxError;
 use sieve::compiler::grammar as sieve_grammar;
 use sieve::compiler::grammar::instruction::Instruction;
+use std::rc::Rc;
 
+mod address;
 mod body;
+mod comparator;
 mod editheader;
+mod envelope;
+mod exists;
 mod fileinto;
+mod header;
+mod include;
+mod keep;
+mod list;
+mod mirror;
+mod redirect;
+mod regex;
 mod reject;
 mod relational;
+mod size;
 mod spamtest;
 mod test;
 mod vacation;
+mod variables;
 
 pub(crate) struct CodeGen<'a> {
     instructions: &'a [Instruction],
     cursor: usize,
     buffer: buffer::Buffer,
     opts: GenerateOpts,
+    /// JavaScript helper functions used by the generated code, emitted once
+    /// after the `run` function.
+    helpers: Vec<&'static str>,
+    /// Module imports added by the generated code, at the top of the file.
+    imports: Vec<String>,
+    /// The scripts included by the execution, whatever the script including
+    /// them.
+    includes: Rc<Vec<include::IncludedScript>>,
+    /// Whether the code is the one of an included script.
+    in_include: bool,
 }
 
 impl<'a> CodeGen<'a> {
@@ -26,9 +50,59 @@ impl<'a> CodeGen<'a> {
             instructions,
             cursor: 0,
             buffer: buffer::Buffer::new(),
+            helpers: vec![],
+            imports: vec![],
+            includes: Rc::new(vec![]),
+            in_include: false,
         }
     }
 
+    pub(crate) fn add_helper(&mut self, helper: &'static str) {
+        if !self.helpers.contains(&helper) {
+            self.helpers.push(helper);
+        }
+    }
+
+    pub(crate) fn add_import(&mut self, import: String) {
+        if !self.imports.contains(&import) {
+            self.imports.push(import);
+        }
+    }
+
+    /// Every instruction of the execution, the ones of the included scripts
+    /// included.
+    fn execution_instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.instructions.iter().chain(
+            self.includes
+                .iter()
+                .flat_map(|included| included.script.instructions.iter()),
+        )
+    }
+
+    /// Whether the generated code reads the message content (`raw` or
+    /// `parsedMessage`). Scripts that only look at the size and the envelope
+    /// don't buffer and parse the message at all.
+    fn needs_message_content(&self) -> bool {
+        self.execution_instructions().any(|instr| match instr {
+            Instruction::Test(n) => test::needs_message_content(n),
+            Instruction::Require(_)
+            | Instruction::Jmp(_)
+            | Instruction::Jz(_)
+            | Instruction::Jnz(_)
+            | Instruction::Stop
+            | Instruction::Discard
+            | Instruction::Keep(_)
+            | Instruction::Redirect(_)
+            | Instruction::Reject(_)
+            | Instruction::AddHeader(_)
+            | Instruction::Set(_)
+            | Instruction::Clear(_)
+            | Instruction::Vacation(_)
+            | Instruction::Return => false,
+            instr => !matches!(mirror::Include::from_instruction(instr), Ok(Some(_))),
+        })
+    }
+
     fn eat(&mut self) -> &'a Instruction {
         let instr = &self.instructions[self.cursor];
         self.cursor += 1;
@@ -37,11 +111,18 @@ impl<'a> CodeGen<'a> {
     }
 
     pub(crate) fn generate_js(&mut self) -> Result<String, BoxError> {
-        self.buffer.write("import PostalMime from \"postal-mime\";");
+        self.includes = Rc::new(include::load_includes(&self.opts, self.instructions)?);
+        reject::check_reject_compatibility(self.instructions, &self.includes)?;
+
+        self.buffer
+            .write("import PostalMime, { addressParser } from \"postal-mime\";");
         self.buffer.newline();
 
-        self.buffer.write(
-            r#"
+        let needs_message_content = self.needs_message_content();
+
+        if needs_message_content {
+            self.buffer.write(
+                r#"
             async function streamToArrayBuffer(stream, streamSize) {
               let result = new Uint8Array(streamSize);
               let bytesRead = 0;
@@ -57,23 +138,39 @@ impl<'a> CodeGen<'a> {
               return result;
             }
             "#,
-        );
-        self.buffer.newline();
+            );
+            self.buffer.newline();
+        }
 
+        // Global variables (RFC 5229, section 5) are owned by the caller, which
+        // can share them between the scripts it runs.
         self.buffer
-            .write("export async function run({ message, env }) {");
+            .write("export async function run({ message, env, globals = {} }) {");
         self.buffer.newline();
 
         self.buffer.write("const extraHeaders = new Headers;");
+        if self
+            .execution_instructions()
+            .any(|instr| matches!(instr, Instruction::Redirect(_)))
+        {
+            self.buffer.write("const redirected = new Set();");
+        }
+        if !self.includes.is_empty() {
+            self.buffer.write("const included = new Set();");
+        }
 
-        self.buffer
-            .write("const raw = await streamToArrayBuffer(message.raw, message.rawSize);");
-        self.buffer.newline();
-        self.buffer
-            .write("const parsedMessage = await PostalMime.parse(raw);");
-        self.buffer.newline();
+        if needs_message_content {
+            self.buffer
+                .write("const raw = await streamToArrayBuffer(message.raw, message.rawSize);");
+            self.buffer.newline();
+            self.buffer
+                .write("const parsedMessage = await PostalMime.parse(raw);");
+            self.buffer.newline();
+        }
+
+        keep::generate_implicit_keep_start(self);
 
-        if self.opts.debug {
+        if self.opts.debug && needs_message_content {
             self.buffer
                 .write("console.log('parsedMessage headers', parsedMessage.headers);");
             self.buffer
@@ -93,14 +190,20 @@ impl<'a> CodeGen<'a> {
             generate_instr(self, instr)?;
         }
 
-        // TODO: sieve has a default keep rule but how to do it
-        // in email workers? We don't know where to forward by default.
-        // self.buffer.newline();
-        // self.buffer.write("await message.forward('default here',extraHeaders);");
+        keep::generate_implicit_keep_end(self)?;
+        include::generate_included_scripts(self)?;
 
         self.buffer.newline();
         self.buffer.write("}");
 
+        for helper in &self.helpers {
+            self.buffer.newline();
+            self.buffer.write(helper);
+        }
+        for import in self.imports.iter().rev() {
+            self.buffer.write_prepend(import);
+        }
+
         Ok(self.buffer.to_string())
     }
 }
@@ -114,7 +217,7 @@ pub(crate) fn generate_instr(ctx: &mut CodeGen, instr: &Instruction) -> Result<(
             ctx.buffer.newline();
             ctx.buffer.write("try {");
 
-            test::generate_test(ctx, &n)?;
+            test::generate_test(ctx, n)?;
 
             ctx.buffer.newline();
             ctx.buffer.write("} catch (err) {");
@@ -124,136 +227,112 @@ pub(crate) fn generate_instr(ctx: &mut CodeGen, instr: &Instruction) -> Result<(
             ctx.buffer.write(&format!(
                 "console.error('rule {rule_id} failed and has been skipped', err);"
             ));
+            ctx.buffer.write("failed = true;");
             ctx.buffer.write("}");
         }
-        Instruction::Reject(n) => reject::generate_reject(ctx, &n)?,
-        Instruction::Redirect(n) => generate_redirect(ctx, &n)?,
-        Instruction::AddHeader(n) => editheader::generate_add_header(ctx, &n)?,
+        Instruction::Reject(n) => reject::generate_reject(ctx, n)?,
+        Instruction::Redirect(n) => redirect::generate_redirect(ctx, n)?,
+        Instruction::AddHeader(n) => editheader::generate_add_header(ctx, n)?,
         Instruction::Discard => {
             if ctx.opts.debug {
                 ctx.buffer.write("console.log(\"discard\");");
             }
             ctx.buffer.write("// discard the email");
             ctx.buffer.newline();
-            ctx.buffer.write("return;");
+            keep::generate_cancel_implicit_keep(ctx);
         }
         Instruction::Stop => {
             if ctx.opts.debug {
                 ctx.buffer.write("console.log(\"stop\");");
             }
-            ctx.buffer.write("return;");
+            include::generate_stop(ctx);
         }
-        Instruction::Keep(_) => {
-            ctx.buffer.write("// keep the email");
+        Instruction::Return => {
+            if ctx.opts.debug {
+                ctx.buffer.write("console.log(\"return\");");
+            }
+            include::generate_return(ctx);
         }
+        Instruction::Keep(n) => keep::generate_keep(ctx, n)?,
         Instruction::Require(n) => generate_require(ctx, n)?,
-        Instruction::Vacation(n) => vacation::generate_vacation(ctx, &n)?,
-        Instruction::Set(n) => generate_set(ctx, &n)?,
-        Instruction::Clear(n) => generate_clear(ctx, &n)?,
-        Instruction::FileInto(n) => fileinto::generate_fileinto(ctx, &n)?,
-
-        e => todo!("{:?}", e),
+        Instruction::Vacation(n) => vacation::generate_vacation(ctx, n)?,
+        Instruction::Set(n) => variables::generate_set(ctx, n)?,
+        Instruction::Clear(n) => variables::generate_clear(ctx, n)?,
+        Instruction::FileInto(n) => fileinto::generate_fileinto(ctx, n)?,
+        e => match mirror::Include::from_instruction(e)? {
+            Some(node) => include::generate_include(ctx, &node)?,
+            None => return Err(format!("instruction not implemented: {e:?}").into()),
+        },
     }
 
     Ok(())
 }
 
-fn generate_redirect(
-    ctx: &mut CodeGen,
-    node: &sieve_grammar::actions::action_redirect::Redirect,
-) -> Result<(), BoxError> {
-    if ctx.opts.debug {
-        ctx.buffer.write("console.log(\"forward\");");
-    }
-
-    ctx.buffer.write("await message.forward(");
-    generate_value(ctx, &node.address)?;
-    ctx.buffer.write(",extraHeaders);");
-    Ok(())
-}
-
 fn generate_require(
     ctx: &mut CodeGen,
     capabilities: &[sieve_grammar::Capability],
 ) -> Result<(), BoxError> {
     for capability in capabilities {
-        match capability {
-            sieve_grammar::Capability::Variables => {
-                ctx.buffer.write("const variables = {};");
-            }
-
-            sieve_grammar::Capability::SpamTestPlus => {
-                spamtest::generate_require_spamtest(ctx)?;
-            }
-            _ => {}
+        if *capability == sieve_grammar::Capability::Variables {
+            ctx.buffer.write("const variables = {};");
         }
     }
 
     Ok(())
 }
 
-fn generate_clear(ctx: &mut CodeGen, node: &sieve_grammar::Clear) -> Result<(), BoxError> {
-    if node.local_vars_idx != 0 {
-        return Err(format!("unsupported local_vars_idx: {}", node.local_vars_idx).into());
-    }
-    if node.match_vars != 0 {
-        return Err(format!("unsupported match_vars: {}", node.match_vars).into());
-    }
-
-    ctx.buffer.write("delete variables[");
-    ctx.buffer
-        .write_quoted(&format!("local{}", node.local_vars_num));
-    ctx.buffer.write("];");
-
-    Ok(())
-}
-
-fn generate_set(
+pub(crate) fn generate_value(
     ctx: &mut CodeGen,
-    node: &sieve_grammar::actions::action_set::Set,
+    node: &sieve::compiler::Value,
 ) -> Result<(), BoxError> {
-    if node.modifiers.len() != 0 {
-        return Err(format!("unsupported modifiers len: {}", node.modifiers.len()).into());
-    }
-
-    ctx.buffer.write("variables[");
-
-    match &node.name {
-        sieve::compiler::VariableType::Local(idx) => {
-            ctx.buffer.write_quoted(&format!("local{idx}"));
+    match node {
+        sieve::compiler::Value::Text(s) => {
+            ctx.buffer.write_quoted(s);
+        }
+        // sieve-rs only parses the strings it can print back as they were,
+        // they stay strings: only `i;ascii-numeric` compares them as numbers.
+        sieve::compiler::Value::Number(n) => {
+            ctx.buffer.write_quoted(&n.to_string());
+        }
+        sieve::compiler::Value::Regex(r) => {
+            ctx.buffer.write_quoted(&r.expr);
+        }
+        // A string with variables, sieve-rs splits it into a list of the
+        // parts to concatenate.
+        sieve::compiler::Value::Variable(_) | sieve::compiler::Value::List(_) => {
+            ctx.buffer.write("`");
+            generate_template_part(ctx, node)?;
+            ctx.buffer.write("`");
         }
-        e => return Err(format!("variable type not implemented: {e:?}").into()),
     }
-
-    ctx.buffer.write("] = ");
-    generate_value(ctx, &node.value)?;
-
-    ctx.buffer.newline();
     Ok(())
 }
 
-pub(crate) fn generate_value(
+fn generate_template_part(
     ctx: &mut CodeGen,
     node: &sieve::compiler::Value,
 ) -> Result<(), BoxError> {
     match node {
         sieve::compiler::Value::Text(s) => {
-            ctx.buffer.write_quoted(s);
+            ctx.buffer.write(&buffer::escape_js_string(s, '`'));
         }
         sieve::compiler::Value::Number(n) => {
             ctx.buffer.write(&n.to_string());
         }
+        sieve::compiler::Value::Regex(r) => {
+            ctx.buffer.write(&buffer::escape_js_string(&r.expr, '`'));
+        }
+        // Unset variables expand to the empty string.
+        sieve::compiler::Value::Variable(var) => {
+            ctx.buffer.write("${");
+            variables::generate_variable(ctx, var)?;
+            ctx.buffer.write(" ?? \"\"}");
+        }
         sieve::compiler::Value::List(list) => {
-            ctx.buffer.write("[");
-
             for item in list {
-                generate_value(ctx, item)?;
-                ctx.buffer.write(",");
+                generate_template_part(ctx, item)?;
             }
-
-            ctx.buffer.write("]");
         }
-        e => todo!("value not implemented {:?}", e),
     }
     Ok(())
 }
@@ -272,44 +351,82 @@ mod tests {
             ),
             (
                 sieve::compiler::Value::Number(sieve::compiler::Number::Integer(3)),
-                "3",
+                r#""3""#,
             ),
             (
                 sieve::compiler::Value::Number(sieve::compiler::Number::Float(3.1)),
-                "3.1",
+                r#""3.1""#,
+            ),
+            (
+                sieve::compiler::Value::Text(Arc::new("a\"b\\c\nd".to_owned())),
+                r#""a\"b\\c\u000ad""#,
             ),
             (
                 sieve::compiler::Value::List(vec![
                     sieve::compiler::Value::Text(Arc::new("a".to_owned())),
                     sieve::compiler::Value::Text(Arc::new("b".to_owned())),
                 ]),
-                r#"["a","b",]"#,
+                "`ab`",
+            ),
+            (
+                sieve::compiler::Value::Variable(sieve::compiler::VariableType::Local(2)),
+                r#"`${variables["local2"] ?? ""}`"#,
+            ),
+            (
+                sieve::compiler::Value::List(vec![
+                    sieve::compiler::Value::Text(Arc::new("INBOX.`${x}`.".to_owned())),
+                    sieve::compiler::Value::Variable(sieve::compiler::VariableType::Match(1)),
+                    sieve::compiler::Value::Text(Arc::new("-".to_owned())),
+                    sieve::compiler::Value::Variable(sieve::compiler::VariableType::Global(
+                        "g".to_owned(),
+                    )),
+                ]),
+                r#"`INBOX.\`\${x}\`.${variables["match1"] ?? ""}-${globals["g"] ?? ""}`"#,
             ),
         ];
 
         for (input, expected) in test_cases {
             let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
-            generate_value(&mut ctx, &input).unwrap();
+            generate_value(&mut ctx, input).unwrap();
             assert_eq!(ctx.buffer.to_string(), expected.to_string());
         }
     }
 
     #[test]
-    fn test_generate_redirect() {
-        let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
-        let input = sieve_grammar::actions::action_redirect::Redirect {
-            address: sieve::compiler::Value::Text(Arc::new("foo reason".to_owned())),
-            copy: false,
-            notify: sieve_grammar::actions::action_redirect::Notify::Never,
-            return_of_content: sieve_grammar::actions::action_redirect::Ret::Default,
-            by_time: sieve_grammar::actions::action_redirect::ByTime::None,
-            list: false,
-        };
-
-        generate_redirect(&mut ctx, &input).unwrap();
-        assert_eq!(
-            ctx.buffer.to_string(),
-            "await message.forward(\"foo reason\",extraHeaders);"
-        );
+    fn test_generate_js_message_content() {
+        let test_cases = &[
+            (
+                r#"require "envelope"; if anyof(size :over 10M, envelope "to" "a@b.c") { discard; }"#,
+                false,
+            ),
+            (r#"if size :over 10M { discard; }"#, false),
+            (r#"if header :is "subject" "a" { discard; }"#, true),
+            (r#"if size :under 1K { redirect "a@b.c"; }"#, false),
+        ];
+
+        for (input, needs_message_content) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert_eq!(js.contains("streamToArrayBuffer"), *needs_message_content);
+            assert_eq!(js.contains("PostalMime.parse"), *needs_message_content);
+        }
+    }
+
+    #[test]
+    fn test_generate_js_variables() {
+        let script = sieve::Compiler::new()
+            .compile(
+                br#"require "variables"; set "target" "a@example.com"; if string :is "${target}" "a@example.com" { redirect "${target}"; }"#,
+            )
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+        let js = ctx.generate_js().unwrap();
+
+        assert!(js.contains(r#"if ([`${variables["local0"] ?? ""}`,].some((value) => sieveIs(value, "a@example.com", "i;ascii-casemap"))) {"#));
+        assert!(js.contains(
+            r#"await sieveRedirect(message, redirected, `${variables["local0"] ?? ""}`, extraHeaders);"#
+        ));
     }
 }
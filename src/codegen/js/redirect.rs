@@ -0,0 +1,115 @@
+use crate::codegen::js::{generate_value, keep, list, BoxError, CodeGen};
+use sieve::compiler::grammar::actions::action_redirect::{ByTime, Notify, Redirect, Ret};
+
+/// Forwards the message once per address (RFC 5228, section 2.10.3).
+const SIEVE_REDIRECT: &str = r#"
+async function sieveRedirect(message, redirected, address, extraHeaders) {
+  const key = address.toLowerCase();
+  if (redirected.has(key)) {
+    return;
+  }
+  redirected.add(key);
+  await message.forward(address, extraHeaders);
+}
+"#;
+
+pub(crate) fn generate_redirect(ctx: &mut CodeGen, node: &Redirect) -> Result<(), BoxError> {
+    // `message.forward` has no DSN (RFC 3461) or deliver-by (RFC 2852)
+    // parameters, ignoring them would lose what the script asked for.
+    if node.notify != Notify::Default {
+        return Err("unsupported :notify in redirect, Email Workers don't support DSN".into());
+    }
+    if node.return_of_content != Ret::Default {
+        return Err("unsupported :ret in redirect, Email Workers don't support DSN".into());
+    }
+    if node.by_time != ByTime::None {
+        return Err(
+            "unsupported :bytimerelative/:bytimeabsolute in redirect, Email Workers don't support deliver-by"
+                .into(),
+        );
+    }
+
+    if ctx.opts.debug {
+        ctx.buffer.write("console.log(\"forward\");");
+    }
+
+    ctx.add_helper(SIEVE_REDIRECT);
+
+    // A :list redirect goes to every member of the list (RFC 6134), the
+    // message is kept when the list is empty.
+    if node.list {
+        ctx.buffer.write("{let sent = false;");
+        ctx.buffer.write("for (const address of ");
+        list::generate_list_members(ctx, std::slice::from_ref(&node.address))?;
+        ctx.buffer.write(
+            ") {await sieveRedirect(message, redirected, address, extraHeaders);sent = true;}",
+        );
+        if !node.copy {
+            ctx.buffer.write("if (sent) {");
+            keep::generate_cancel_implicit_keep(ctx);
+            ctx.buffer.write("}");
+        }
+        ctx.buffer.write("}");
+    } else {
+        ctx.buffer
+            .write("await sieveRedirect(message, redirected, ");
+        generate_value(ctx, &node.address)?;
+        ctx.buffer.write(", extraHeaders);");
+        if !node.copy {
+            keep::generate_cancel_implicit_keep(ctx);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_redirect() {
+        let test_cases = &[
+            (
+                false,
+                "await sieveRedirect(message, redirected, \"a@example.com\", extraHeaders);implicitKeep = false;",
+            ),
+            (
+                true,
+                "await sieveRedirect(message, redirected, \"a@example.com\", extraHeaders);",
+            ),
+        ];
+
+        for (copy, expected) in test_cases {
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+            let input = Redirect {
+                address: sieve::compiler::Value::Text(Arc::new("a@example.com".to_owned())),
+                copy: *copy,
+                notify: Notify::Default,
+                return_of_content: Ret::Default,
+                by_time: ByTime::None,
+                list: false,
+            };
+
+            generate_redirect(&mut ctx, &input).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+        }
+    }
+
+    #[test]
+    fn test_generate_redirect_unsupported() {
+        let test_cases = &[
+            r#"require "redirect-dsn"; redirect :notify "never" "a@example.com";"#,
+            r#"require "redirect-dsn"; redirect :ret "hdrs" "a@example.com";"#,
+            r#"require "redirect-deliverby"; redirect :bytimerelative 600 "a@example.com";"#,
+        ];
+
+        for input in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+
+            ctx.generate_js().unwrap_err();
+        }
+    }
+}
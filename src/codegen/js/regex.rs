@@ -0,0 +1,503 @@
+//! The `:regex` match type (draft-ietf-sieve-regex) takes POSIX extended
+//! regular expressions, they are translated into JavaScript regex literals.
+
+use crate::codegen::js::comparator::{push_js_regex_char, regex_case_map, SIEVE_REGEX_CHAR};
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+use sieve::compiler::grammar as sieve_grammar;
+
+/// Translates a POSIX extended regular expression known only at runtime,
+/// like `posix_to_js_regex`.
+const SIEVE_POSIX_REGEX: &str = r#"
+const sievePosixClasses = {
+  alpha: [["a", "z"], ["A", "Z"]],
+  digit: [["0", "9"]],
+  alnum: [["a", "z"], ["A", "Z"], ["0", "9"]],
+  upper: [["A", "Z"]],
+  lower: [["a", "z"]],
+  space: [[" ", " "], ["\t", "\r"]],
+  blank: [[" ", " "], ["\t", "\t"]],
+  punct: [["!", "/"], [":", "@"], ["[", "`"], ["{", "~"]],
+  print: [[" ", "~"]],
+  graph: [["!", "~"]],
+  cntrl: [["\0", "\x1f"], ["\x7f", "\x7f"]],
+  xdigit: [["0", "9"], ["A", "F"], ["a", "f"]],
+};
+
+function sievePosixRegex(pattern, caseMap) {
+  const chars = [...pattern];
+  const invalid = (reason) => new Error(`invalid regex ${JSON.stringify(pattern)}: ${reason}`);
+  let regex = "";
+  let repeatable = false;
+  for (let i = 0; i < chars.length; i++) {
+    const c = chars[i];
+    if (c === "\\") {
+      const escaped = chars[++i];
+      if (escaped === undefined) {
+        throw invalid("trailing backslash");
+      }
+      if (!"^.[]$()|*+?{}\\/dDwWsSbBntr".includes(escaped)) {
+        throw invalid(`unsupported escape \\${escaped}`);
+      }
+      regex += `\\${escaped}`;
+      repeatable = escaped !== "b" && escaped !== "B";
+    } else if (c === "(" && chars[i + 1] === "?") {
+      throw invalid("unsupported (? group");
+    } else if (c === "[") {
+      let j = i + 1;
+      const negated = chars[j] === "^";
+      if (negated) {
+        j++;
+      }
+      const ranges = [];
+      if (chars[j] === "]") {
+        ranges.push(["]", "]"]);
+        j++;
+      }
+      for (;;) {
+        const first = chars[j++];
+        if (first === undefined) {
+          throw invalid("unterminated bracket expression");
+        }
+        if (first === "]") {
+          break;
+        }
+        if (first === "[" && chars[j] === ":") {
+          const end = chars.findIndex((d, k) => k > j && d === ":" && chars[k + 1] === "]");
+          if (end === -1) {
+            throw invalid("unterminated character class");
+          }
+          const name = chars.slice(j + 1, end).join("");
+          if (!Object.hasOwn(sievePosixClasses, name)) {
+            throw invalid(`unknown character class [:${name}:]`);
+          }
+          ranges.push(...sievePosixClasses[name]);
+          j = end + 2;
+          continue;
+        }
+        if (first === "[" && (chars[j] === "=" || chars[j] === ".")) {
+          throw invalid("unsupported collating element");
+        }
+        if (chars[j] === "-" && chars[j + 1] !== undefined && chars[j + 1] !== "]") {
+          const last = chars[j + 1];
+          if (last.codePointAt(0) < first.codePointAt(0)) {
+            throw invalid("invalid range");
+          }
+          ranges.push([first, last]);
+          j += 2;
+        } else {
+          ranges.push([first, first]);
+        }
+      }
+      if (caseMap) {
+        for (const [first, last] of [...ranges]) {
+          for (const [lower, upper] of [["a", "z"], ["A", "Z"]]) {
+            const from = first > lower ? first : lower;
+            const to = last < upper ? last : upper;
+            const fold = (d) => (lower === "a" ? d.toUpperCase() : d.toLowerCase());
+            if (from <= to && !ranges.some(([f, l]) => f === fold(from) && l === fold(to))) {
+              ranges.push([fold(from), fold(to)]);
+            }
+          }
+        }
+      }
+      const escape = (d) => (/^[\\\]\[^\-/]$/.test(d) ? `\\${d}` : d);
+      regex += `[${negated ? "^" : ""}`;
+      for (const [first, last] of ranges) {
+        regex += first === last ? escape(first) : `${escape(first)}-${escape(last)}`;
+      }
+      regex += "]";
+      i = j - 1;
+      repeatable = true;
+    } else if ("*+?{".includes(c)) {
+      if (!repeatable) {
+        throw invalid("nothing to repeat");
+      }
+      if (c === "{") {
+        const bound = /^\{([0-9]+)(,([0-9]*))?\}/.exec(chars.slice(i).join(""));
+        if (bound === null || (bound[3] && Number(bound[3]) < Number(bound[1]))) {
+          throw invalid("invalid bound");
+        }
+        regex += bound[0];
+        i += bound[0].length - 1;
+      } else {
+        regex += c;
+      }
+      repeatable = false;
+    } else if ("^(|".includes(c)) {
+      regex += c;
+      repeatable = false;
+    } else if (".$)".includes(c)) {
+      regex += c;
+      repeatable = c !== "$";
+    } else {
+      regex += sieveRegexChar(c, caseMap);
+      repeatable = true;
+    }
+  }
+  return new RegExp(regex, "su");
+}
+"#;
+
+/// Translates a POSIX extended regular expression into a JavaScript regex
+/// literal, failing on the constructs without JavaScript equivalent.
+pub(crate) fn posix_to_js_regex(
+    pattern: &str,
+    comparator: &sieve_grammar::Comparator,
+) -> Result<String, BoxError> {
+    // ASCII letters are folded by hand, like for :matches, the `i` flag
+    // would also fold non-ASCII letters.
+    let case_map = regex_case_map(comparator, ":regex")?;
+
+    let mut regex = String::from("/");
+    // Whether the previous atom can take a quantifier, JavaScript refuses the
+    // ones POSIX leaves undefined.
+    let mut repeatable = false;
+    let mut chars = pattern.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((
+                    _,
+                    c @ ('^' | '.' | '[' | ']' | '$' | '(' | ')' | '|' | '*' | '+' | '?' | '{'
+                    | '}' | '\\' | '/'),
+                )) => {
+                    regex.push('\\');
+                    regex.push(c);
+                    repeatable = true;
+                }
+                // Common extensions meaning the same in JavaScript.
+                Some((_, c @ ('d' | 'D' | 'w' | 'W' | 's' | 'S' | 'n' | 't' | 'r'))) => {
+                    regex.push('\\');
+                    regex.push(c);
+                    repeatable = true;
+                }
+                Some((_, c @ ('b' | 'B'))) => {
+                    regex.push('\\');
+                    regex.push(c);
+                    repeatable = false;
+                }
+                Some((_, c)) => {
+                    return Err(format!(
+                        "invalid regex {pattern:?}: unsupported escape \\{c} at {pos}"
+                    )
+                    .into())
+                }
+                None => return Err(format!("invalid regex {pattern:?}: trailing backslash").into()),
+            },
+            '(' if chars.peek().map(|(_, c)| *c) == Some('?') => {
+                return Err(
+                    format!("invalid regex {pattern:?}: unsupported (? group at {pos}").into(),
+                );
+            }
+            '[' => {
+                push_bracket_expression(&mut regex, &mut chars, pattern, pos, case_map)?;
+                repeatable = true;
+            }
+            '*' | '+' | '?' | '{' => {
+                if !repeatable {
+                    return Err(
+                        format!("invalid regex {pattern:?}: nothing to repeat at {pos}").into(),
+                    );
+                }
+                regex.push(c);
+                if c == '{' {
+                    push_bound(&mut regex, &mut chars, pattern, pos)?;
+                }
+                repeatable = false;
+            }
+            '^' | '(' | '|' => {
+                regex.push(c);
+                repeatable = false;
+            }
+            '.' | '$' | ')' => {
+                regex.push(c);
+                repeatable = c != '$';
+            }
+            c => {
+                push_js_regex_char(&mut regex, c, case_map);
+                repeatable = true;
+            }
+        }
+    }
+    // `.` matches any character in POSIX, line terminators included. The `u`
+    // flag makes it match a whole character rather than a UTF-16 code unit.
+    regex.push_str("/su");
+
+    Ok(regex)
+}
+
+/// Generates the regex of a pattern with variables, their values are
+/// expanded into the pattern before it's translated.
+pub(crate) fn generate_posix_regex(
+    ctx: &mut CodeGen,
+    comparator: &sieve_grammar::Comparator,
+    key: &sieve::compiler::Value,
+) -> Result<(), BoxError> {
+    let case_map = regex_case_map(comparator, ":regex")?;
+
+    ctx.add_helper(SIEVE_REGEX_CHAR);
+    ctx.add_helper(SIEVE_POSIX_REGEX);
+
+    ctx.buffer.write("sievePosixRegex(");
+    generate_value(ctx, key)?;
+    ctx.buffer.write(&format!(", {case_map})"));
+
+    Ok(())
+}
+
+/// Pushes the bound `{m}`, `{m,}` or `{m,n}` starting after the `{` at
+/// `start`.
+fn push_bound(
+    regex: &mut String,
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    pattern: &str,
+    start: usize,
+) -> Result<(), BoxError> {
+    let mut bound = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '}')) => break,
+            Some((_, c)) if c.is_ascii_digit() || c == ',' => bound.push(c),
+            _ => return Err(format!("invalid regex {pattern:?}: invalid bound at {start}").into()),
+        }
+    }
+
+    let (min, max) = bound.split_once(',').unwrap_or((&bound, &bound));
+    let valid = match (min.parse::<u32>(), max) {
+        (Ok(_), "") => true,
+        (Ok(min), max) => max.parse::<u32>().is_ok_and(|max| min <= max),
+        (Err(_), _) => false,
+    };
+    if !valid {
+        return Err(format!("invalid regex {pattern:?}: invalid bound at {start}").into());
+    }
+
+    regex.push_str(&bound);
+    regex.push('}');
+
+    Ok(())
+}
+
+/// Pushes the bracket expression starting after the `[` at `start`.
+fn push_bracket_expression(
+    regex: &mut String,
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    pattern: &str,
+    start: usize,
+    case_map: bool,
+) -> Result<(), BoxError> {
+    let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+    let mut ranges = vec![];
+    // A leading `]` is part of the list.
+    if chars.next_if(|(_, c)| *c == ']').is_some() {
+        ranges.push((']', ']'));
+    }
+
+    loop {
+        let Some((pos, c)) = chars.next() else {
+            return Err(format!(
+                "invalid regex {pattern:?}: unterminated bracket expression at {start}"
+            )
+            .into());
+        };
+        let first = match c {
+            ']' => break,
+            '[' if chars.peek().map(|(_, c)| *c) == Some(':') => {
+                chars.next();
+                let rest = &pattern[pos + 2..];
+                let end = rest.find(":]").ok_or_else(|| {
+                    format!("invalid regex {pattern:?}: unterminated character class at {pos}")
+                })?;
+                let name = &rest[..end];
+                ranges.extend_from_slice(posix_class(name).ok_or_else(|| {
+                    format!("invalid regex {pattern:?}: unknown character class [:{name}:]")
+                })?);
+                for _ in 0..name.chars().count() + 2 {
+                    chars.next();
+                }
+                continue;
+            }
+            '[' if matches!(chars.peek(), Some((_, '=' | '.'))) => {
+                return Err(format!(
+                    "invalid regex {pattern:?}: unsupported collating element at {pos}"
+                )
+                .into());
+            }
+            c => c,
+        };
+
+        // A `-` is a range unless it ends the list.
+        let mut lookahead = chars.clone();
+        match (lookahead.next(), lookahead.next()) {
+            (Some((_, '-')), Some((_, last))) if last != ']' => {
+                chars.next();
+                chars.next();
+                if last < first {
+                    return Err(format!("invalid regex {pattern:?}: invalid range at {pos}").into());
+                }
+                ranges.push((first, last));
+            }
+            _ => ranges.push((first, first)),
+        }
+    }
+
+    if case_map {
+        for (first, last) in ranges.clone() {
+            for (lower, upper) in [('a', 'z'), ('A', 'Z')] {
+                let (first, last) = (first.max(lower), last.min(upper));
+                if first > last {
+                    continue;
+                }
+                let folded = if lower == 'a' {
+                    (first.to_ascii_uppercase(), last.to_ascii_uppercase())
+                } else {
+                    (first.to_ascii_lowercase(), last.to_ascii_lowercase())
+                };
+                if !ranges.contains(&folded) {
+                    ranges.push(folded);
+                }
+            }
+        }
+    }
+
+    regex.push('[');
+    if negated {
+        regex.push('^');
+    }
+    for (first, last) in ranges {
+        push_bracket_char(regex, first);
+        if last != first {
+            regex.push('-');
+            push_bracket_char(regex, last);
+        }
+    }
+    regex.push(']');
+
+    Ok(())
+}
+
+/// Pushes `c` so that it only matches itself in a JavaScript bracket
+/// expression. A backslash has no special meaning in a POSIX one.
+fn push_bracket_char(regex: &mut String, c: char) {
+    match c {
+        '\\' | ']' | '[' | '^' | '-' | '/' => {
+            regex.push('\\');
+            regex.push(c);
+        }
+        c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+            regex.push_str(&format!("\\u{:04x}", c as u32));
+        }
+        c => regex.push(c),
+    }
+}
+
+fn posix_class(name: &str) -> Option<&'static [(char, char)]> {
+    Some(match name {
+        "alpha" => &[('a', 'z'), ('A', 'Z')],
+        "digit" => &[('0', '9')],
+        "alnum" => &[('a', 'z'), ('A', 'Z'), ('0', '9')],
+        "upper" => &[('A', 'Z')],
+        "lower" => &[('a', 'z')],
+        "space" => &[(' ', ' '), ('\t', '\r')],
+        "blank" => &[(' ', ' '), ('\t', '\t')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "print" => &[(' ', '~')],
+        "graph" => &[('!', '~')],
+        "cntrl" => &[('\0', '\x1f'), ('\x7f', '\x7f')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::js::CodeGen;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_regex_match() {
+        let test_cases = &[
+            (
+                r#"require "regex"; if header :regex "Subject" "^\\[JIRA-[0-9]+\\]" { stop; }"#,
+                r#"sieveHeaderValues(parsedMessage, ["Subject",]).some((value) => /^\[[jJ][iI][rR][aA]-[0-9]+\]/su.test(value))"#,
+            ),
+            (
+                r#"require ["regex", "variables"]; if header :regex "Subject" "^\\[JIRA-([0-9]+)\\]" { set "ticket" "${1}"; }"#,
+                r#"sieveHeaderValues(parsedMessage, ["Subject",]).some((value) => sieveMatch(variables, value, /^\[[jJ][iI][rR][aA]-([0-9]+)\]/su))"#,
+            ),
+            (
+                r#"require ["regex", "envelope"]; if envelope :regex :comparator "i;octet" "from" ["^bounce", "^MAILER-DAEMON$"] { stop; }"#,
+                r#".some((value) => (/^bounce/su.test(value) || /^MAILER-DAEMON$/su.test(value)))"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+        }
+    }
+
+    #[test]
+    fn test_posix_to_js_regex() {
+        let test_cases = &[
+            (r"^\[JIRA-[0-9]+\]", r"/^\[[jJ][iI][rR][aA]-[0-9]+\]/su"),
+            ("a|b(c)*d{2,3}.?$", "/[aA]|[bB]([cC])*[dD]{2,3}.?$/su"),
+            ("[[:alpha:]_][[:alnum:]]*", "/[a-zA-Z_][a-zA-Z0-9]*/su"),
+            ("[^]a-]", r"/[^\]a\-A]/su"),
+            ("[a-cX][[:lower:]]", "/[a-cXA-Cx][a-zA-Z]/su"),
+            (r"[\/]", r"/[\\\/]/su"),
+            ("a/b", r"/[aA]\/[bB]/su"),
+            (r"\d+\.\d+", r"/\d+\.\d+/su"),
+            ("a\nb", r"/[aA]\u000a[bB]/su"),
+            // Only ASCII letters are folded.
+            ("É[é]", "/É[é]/su"),
+            ("[😀]x{3,}y{2}", "/[😀][xX]{3,}[yY]{2}/su"),
+            ("a]}", r"/[aA]\]\}/su"),
+        ];
+
+        for (pattern, expected) in test_cases {
+            assert_eq!(
+                &posix_to_js_regex(pattern, &sieve_grammar::Comparator::AsciiCaseMap).unwrap(),
+                expected
+            );
+        }
+
+        assert_eq!(
+            posix_to_js_regex("a.b", &sieve_grammar::Comparator::Octet).unwrap(),
+            "/a.b/su"
+        );
+    }
+
+    #[test]
+    fn test_posix_to_js_regex_invalid() {
+        let test_cases = &[
+            (r"(?i)a", "unsupported (? group at 0"),
+            (r"(a)\1", r"unsupported escape \1 at 3"),
+            ("[[:word:]]", "unknown character class [:word:]"),
+            ("[[=a=]]", "unsupported collating element at 1"),
+            ("[abc", "unterminated bracket expression at 0"),
+            ("[z-a]", "invalid range at 1"),
+            ("a\\", "trailing backslash"),
+            ("x{,3}", "invalid bound at 1"),
+            ("x{3,2}", "invalid bound at 1"),
+            ("x{a}", "invalid bound at 1"),
+            ("x{2", "invalid bound at 1"),
+            ("*a", "nothing to repeat at 0"),
+            ("(+a)", "nothing to repeat at 1"),
+            ("a**", "nothing to repeat at 2"),
+        ];
+
+        for (pattern, expected) in test_cases {
+            let err = posix_to_js_regex(pattern, &sieve_grammar::Comparator::Octet)
+                .unwrap_err()
+                .to_string();
+            assert!(err.ends_with(expected), "{err}");
+        }
+
+        posix_to_js_regex("a", &sieve_grammar::Comparator::AsciiNumeric).unwrap_err();
+    }
+}
@@ -1,16 +1,172 @@
-use crate::codegen::js::{generate_value, BoxError, CodeGen};
+//! Reject and ereject (RFC 5429).
+//!
+//! Email Workers refuse the message during the SMTP transaction with
+//! `message.setReject`, which is what ereject requires and what reject
+//! prefers, so both compile to it. No MDN is ever sent.
+
+use crate::codegen::js::include::{self, IncludedScript};
+use crate::codegen::js::{generate_value, keep, mirror, BoxError, CodeGen};
 use sieve::compiler::grammar as sieve_grammar;
+use sieve::compiler::grammar::instruction::Instruction;
+
+/// The reason ends up in the SMTP reply, which is a single line of ASCII.
+const SIEVE_REJECT_REASON: &str = r#"
+function sieveRejectReason(reason) {
+  return String(reason)
+    .replace(/\s*[\r\n]+\s*/g, " ")
+    .replace(/[^\x20-\x7e]/g, "?")
+    .trim();
+}
+"#;
 
 pub(crate) fn generate_reject(
     ctx: &mut CodeGen,
     node: &sieve_grammar::actions::action_reject::Reject,
 ) -> Result<(), BoxError> {
-    ctx.buffer.write("message.setReject(");
+    if ctx.opts.debug {
+        let action = if node.ereject { "ereject" } else { "reject" };
+        ctx.buffer.write(&format!("console.log(\"{action}\");"));
+    }
+
+    ctx.add_helper(SIEVE_REJECT_REASON);
+
+    ctx.buffer.write("message.setReject(sieveRejectReason(");
     generate_value(ctx, &node.reason)?;
-    ctx.buffer.write(");");
+    ctx.buffer.write("));");
+    keep::generate_cancel_implicit_keep(ctx);
+    Ok(())
+}
+
+/// Fails when a reject can run in the same execution as another reject or as
+/// an action delivering the message or replying to it (RFC 5429, section
+/// 2.1), branches that exclude each other are fine.
+///
+/// The whole execution counts: an `include` takes every action its script
+/// can take, at the point where it's included.
+pub(crate) fn check_reject_compatibility(
+    instructions: &[Instruction],
+    includes: &[IncludedScript],
+) -> Result<(), BoxError> {
+    // A script is only loaded after the ones it includes, an include of a
+    // script being loaded is a loop which only runs it once.
+    let mut script_actions: Vec<Vec<&'static str>> = vec![];
+    for included in includes {
+        let actions =
+            instruction_actions(&included.script.instructions, includes, &script_actions)?;
+        check_actions(&included.script.instructions, &actions)?;
+        script_actions.push(actions.into_iter().flat_map(|a| a.names).collect());
+    }
+
+    let actions = instruction_actions(instructions, includes, &script_actions)?;
+    check_actions(instructions, &actions)
+}
+
+/// The actions of an instruction that matter to reject.
+#[derive(Default)]
+struct Actions {
+    names: Vec<&'static str>,
+    /// The included script and whether it's included `:once`.
+    include: Option<(usize, bool)>,
+}
+
+fn instruction_actions(
+    instructions: &[Instruction],
+    includes: &[IncludedScript],
+    script_actions: &[Vec<&'static str>],
+) -> Result<Vec<Actions>, BoxError> {
+    instructions
+        .iter()
+        .map(|instr| {
+            let name = match instr {
+                Instruction::Redirect(_) => "redirect",
+                Instruction::Vacation(_) => "vacation",
+                Instruction::Keep(_) => "keep",
+                Instruction::FileInto(_) => "fileinto",
+                Instruction::Reject(reject) if reject.ereject => "ereject",
+                Instruction::Reject(_) => "reject",
+                instr => {
+                    let Some(node) = mirror::Include::from_instruction(instr)? else {
+                        return Ok(Actions::default());
+                    };
+                    let Some(idx) = include::find_included(includes, &node)? else {
+                        return Ok(Actions::default());
+                    };
+                    return Ok(Actions {
+                        names: script_actions.get(idx).cloned().unwrap_or_default(),
+                        include: Some((idx, node.once)),
+                    });
+                }
+            };
+            Ok(Actions {
+                names: vec![name],
+                include: None,
+            })
+        })
+        .collect()
+}
+
+fn check_actions(instructions: &[Instruction], actions: &[Actions]) -> Result<(), BoxError> {
+    for (i, first) in actions.iter().enumerate() {
+        for (j, second) in actions.iter().enumerate() {
+            if i == j || !reachable(instructions, i, j) {
+                continue;
+            }
+            // A script included `:once` again doesn't run.
+            if let (Some((a, _)), Some((b, true))) = (first.include, second.include) {
+                if a == b {
+                    continue;
+                }
+            }
+
+            for (reject, other) in [(first, second), (second, first)] {
+                let Some(name) = reject
+                    .names
+                    .iter()
+                    .find(|name| matches!(**name, "reject" | "ereject"))
+                else {
+                    continue;
+                };
+                let action = match other.names.first().copied() {
+                    Some(action @ ("reject" | "ereject")) => format!("another {action}"),
+                    Some(action) => action.to_string(),
+                    None => continue,
+                };
+                return Err(format!("{name} can't be used with {action}").into());
+            }
+        }
+    }
+
     Ok(())
 }
 
+/// Whether the instruction `to` can run after the instruction `from`.
+fn reachable(instructions: &[Instruction], from: usize, to: usize) -> bool {
+    let mut visited = vec![false; instructions.len()];
+    let mut stack = vec![from + 1];
+
+    while let Some(i) = stack.pop() {
+        if i >= instructions.len() || visited[i] {
+            continue;
+        }
+        if i == to {
+            return true;
+        }
+        visited[i] = true;
+
+        match &instructions[i] {
+            Instruction::Jmp(target) => stack.push(*target),
+            Instruction::Jz(target) | Instruction::Jnz(target) => {
+                stack.push(i + 1);
+                stack.push(*target);
+            }
+            Instruction::Stop | Instruction::Return => {}
+            _ => stack.push(i + 1),
+        }
+    }
+
+    false
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -19,13 +175,80 @@ mod tests {
 
     #[test]
     fn test_generate_reject() {
-        let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
-        let input = sieve_grammar::actions::action_reject::Reject {
-            ereject: false,
-            reason: sieve::compiler::Value::Text(Arc::new("foo reason".to_owned())),
-        };
-
-        generate_reject(&mut ctx, &input).unwrap();
-        assert_eq!(ctx.buffer.to_string(), "message.setReject(\"foo reason\");");
+        let test_cases = &[
+            (
+                false,
+                sieve::compiler::Value::Text(Arc::new("foo reason".to_owned())),
+                "message.setReject(sieveRejectReason(\"foo reason\"));implicitKeep = false;",
+            ),
+            (
+                true,
+                sieve::compiler::Value::List(vec![
+                    sieve::compiler::Value::Text(Arc::new("no \"".to_owned())),
+                    sieve::compiler::Value::Variable(sieve::compiler::VariableType::Match(1)),
+                ]),
+                r#"message.setReject(sieveRejectReason(`no "${variables["match1"] ?? ""}`));implicitKeep = false;"#,
+            ),
+        ];
+
+        for (ereject, reason, expected) in test_cases {
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+            let input = sieve_grammar::actions::action_reject::Reject {
+                ereject: *ereject,
+                reason: reason.clone(),
+            };
+
+            generate_reject(&mut ctx, &input).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+        }
+    }
+
+    #[test]
+    fn test_check_reject_compatibility() {
+        let test_cases = &[
+            (r#"require "reject"; reject "no"; stop;"#, true),
+            (r#"require "ereject"; ereject "no";"#, true),
+            (
+                r#"require "reject"; if size :over 1M { reject "too big"; } else { redirect "a@example.com"; }"#,
+                true,
+            ),
+            (
+                r#"require "reject"; if size :over 1M { reject "too big"; stop; } redirect "a@example.com";"#,
+                true,
+            ),
+            (
+                r#"require "reject"; if size :over 1M { reject "too big"; } redirect "a@example.com";"#,
+                false,
+            ),
+            (
+                r#"require "reject"; redirect "a@example.com"; reject "no";"#,
+                false,
+            ),
+            (
+                r#"require ["reject", "vacation"]; vacation "away"; reject "no";"#,
+                false,
+            ),
+            (r#"require "ereject"; keep; ereject "no";"#, false),
+            (
+                r#"require ["ereject", "fileinto"]; ereject "no"; fileinto "r2://BUCKET";"#,
+                false,
+            ),
+            (
+                r#"require "reject"; if size :over 1M { reject "too big"; } elsif header :is "X-A" "b" { reject "no"; }"#,
+                true,
+            ),
+            (
+                r#"require ["reject", "ereject"]; if size :over 1M { reject "too big"; } ereject "no";"#,
+                false,
+            ),
+            (r#"require "reject"; reject "no"; reject "again";"#, false),
+        ];
+
+        for (input, compatible) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+
+            assert_eq!(ctx.generate_js().is_ok(), *compatible, "{input}");
+        }
     }
 }
@@ -1 +1,156 @@
-// TODO: implement https://datatracker.ietf.org/doc/html/rfc3431
+//! Relational tests (RFC 5231).
+
+use crate::codegen::js::comparator::{comparator_name, SIEVE_COMPARE};
+use crate::codegen::js::{generate_value, mirror, BoxError, CodeGen};
+use sieve::compiler::grammar as sieve_grammar;
+
+const SIEVE_RELATIONAL: &str = r#"
+function sieveRelational(value, key, operator, comparator) {
+  const order = sieveCompare(value, key, comparator);
+  switch (operator) {
+    case "gt":
+      return order > 0;
+    case "ge":
+      return order >= 0;
+    case "lt":
+      return order < 0;
+    case "le":
+      return order <= 0;
+    case "eq":
+      return order === 0;
+    default:
+      return order !== 0;
+  }
+}
+"#;
+
+const SIEVE_COUNT: &str = r#"
+function sieveCount(values, keys, operator, comparator) {
+  return keys.some((key) => sieveRelational(values.length, key, operator, comparator));
+}
+"#;
+
+fn operator_name(operator: mirror::RelationalMatch) -> &'static str {
+    match operator {
+        mirror::RelationalMatch::Gt => "gt",
+        mirror::RelationalMatch::Ge => "ge",
+        mirror::RelationalMatch::Lt => "lt",
+        mirror::RelationalMatch::Le => "le",
+        mirror::RelationalMatch::Eq => "eq",
+        mirror::RelationalMatch::Ne => "ne",
+    }
+}
+
+/// Generates the `:value` comparison of the JavaScript expression `value`
+/// against `key`.
+pub(crate) fn generate_value_match(
+    ctx: &mut CodeGen,
+    operator: mirror::RelationalMatch,
+    comparator: &sieve_grammar::Comparator,
+    value: &str,
+    key: &sieve::compiler::Value,
+) -> Result<(), BoxError> {
+    let comparator = comparator_name(comparator)?;
+
+    ctx.add_helper(SIEVE_COMPARE);
+    ctx.add_helper(SIEVE_RELATIONAL);
+
+    ctx.buffer.write(&format!("sieveRelational({value}, "));
+    generate_value(ctx, key)?;
+    ctx.buffer.write(&format!(
+        ", \"{}\", \"{comparator}\")",
+        operator_name(operator)
+    ));
+
+    Ok(())
+}
+
+/// Generates the `:count` comparison of the number of values of the
+/// JavaScript array written by `values` against the keys.
+pub(crate) fn generate_count_match<'a>(
+    ctx: &mut CodeGen<'a>,
+    operator: mirror::RelationalMatch,
+    comparator: &sieve_grammar::Comparator,
+    keys: &[sieve::compiler::Value],
+    values: impl FnOnce(&mut CodeGen<'a>) -> Result<(), BoxError>,
+) -> Result<(), BoxError> {
+    let comparator = comparator_name(comparator)?;
+
+    ctx.add_helper(SIEVE_COMPARE);
+    ctx.add_helper(SIEVE_RELATIONAL);
+    ctx.add_helper(SIEVE_COUNT);
+
+    ctx.buffer.write("sieveCount(");
+    values(ctx)?;
+    ctx.buffer.write(", [");
+    for key in keys {
+        generate_value(ctx, key)?;
+        ctx.buffer.write(",");
+    }
+    ctx.buffer.write(&format!(
+        "], \"{}\", \"{comparator}\")",
+        operator_name(operator)
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_relational() {
+        let test_cases = &[
+            (
+                r#"require "relational"; if header :value "ge" :comparator "i;ascii-numeric" "X-Spam-score" "5" { stop; }"#,
+                r#"sieveHeaderValues(parsedMessage, ["X-Spam-score",]).some((value) => sieveRelational(value, "5", "ge", "i;ascii-numeric"))"#,
+            ),
+            (
+                r#"require "relational"; if header :count "gt" :comparator "i;ascii-numeric" ["Received", "X-Received"] ["3", "4"] { stop; }"#,
+                r#"sieveCount(sieveHeaderValues(parsedMessage, ["Received","X-Received",]), ["3","4",], "gt", "i;ascii-numeric")"#,
+            ),
+            (
+                r#"require "relational"; if address :count "eq" :comparator "i;ascii-numeric" :all ["to", "cc"] "1" { stop; }"#,
+                r#"sieveCount(sieveAddresses(parsedMessage, ["to","cc",]).map((address) => sieveAddressPart(address, "all")), ["1",], "eq", "i;ascii-numeric")"#,
+            ),
+            (
+                r#"require "relational"; if address :value "lt" :domain "from" "m" { stop; }"#,
+                r#"sieveAddresses(parsedMessage, ["from",]).map((address) => sieveAddressPart(address, "domain")).some((value) => sieveRelational(value, "m", "lt", "i;ascii-casemap"))"#,
+            ),
+            (
+                r#"require ["relational", "envelope"]; if envelope :value "ne" :comparator "i;octet" :localpart "from" "MAILER-DAEMON" { stop; }"#,
+                r#"[{ address: message.from },].map((address) => sieveAddressPart(address, "localpart")).some((value) => sieveRelational(value, "MAILER-DAEMON", "ne", "i;octet"))"#,
+            ),
+            (
+                r#"require ["relational", "variables"]; if string :count "le" :comparator "i;ascii-numeric" ["a", ""] "1" { stop; }"#,
+                r#"sieveCount(["a","",].filter((value) => value !== ""), ["1",], "le", "i;ascii-numeric")"#,
+            ),
+            (
+                r#"require ["relational", "variables"]; if not string :value "gt" :comparator "i;ascii-numeric" "10" ["9", "x"] { stop; }"#,
+                r#"!(["10",].some((value) => (sieveRelational(value, "9", "gt", "i;ascii-numeric") || sieveRelational(value, "x", "gt", "i;ascii-numeric"))))"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(&format!("if ({expected}) {{")), "{js}");
+            assert!(js.contains(SIEVE_COMPARE));
+            assert!(js.contains(SIEVE_RELATIONAL));
+        }
+    }
+
+    #[test]
+    fn test_generate_relational_unsupported_comparator() {
+        let script = sieve::Compiler::new()
+            .compile(br#"require ["relational", "comparator-i;unicode-casemap"]; if header :value "eq" :comparator "i;unicode-casemap" "a" "b" { stop; }"#)
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+
+        ctx.generate_js().unwrap_err();
+    }
+}
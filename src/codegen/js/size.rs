@@ -0,0 +1,43 @@
+use crate::codegen::js::test::generate_negatable;
+use crate::codegen::js::{mirror, BoxError, CodeGen};
+
+/// Uses the size announced by Email Workers, so the message doesn't need
+/// to be buffered.
+pub(crate) fn generate_test_size(
+    ctx: &mut CodeGen,
+    node: &mirror::TestSize,
+) -> Result<(), BoxError> {
+    generate_negatable(ctx, node.is_not, |ctx| {
+        let operator = if node.over { ">" } else { "<" };
+        ctx.buffer
+            .write(&format!("message.rawSize {operator} {}", node.limit));
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_test_size() {
+        let test_cases = &[
+            (true, 100, false, "message.rawSize > 100"),
+            (false, 1024, false, "message.rawSize < 1024"),
+            (true, 100, true, "!(message.rawSize > 100)"),
+        ];
+
+        for (over, limit, is_not, expected) in test_cases {
+            let node = mirror::TestSize {
+                over: *over,
+                limit: *limit,
+                is_not: *is_not,
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_test_size(&mut ctx, &node).unwrap();
+            assert_eq!(ctx.buffer.to_string(), expected.to_string());
+        }
+    }
+}
@@ -1,28 +1,286 @@
-use crate::codegen::js::{generate_instr, generate_value, BoxError, CodeGen};
+//! Spamtest, spamtestplus and virustest (RFC 5235).
+
+use crate::codegen::buffer::escape_js_string;
+use crate::codegen::js::test::{generate_negatable, generate_values_match};
+use crate::codegen::js::{mirror, BoxError, CodeGen};
+use crate::codegen::ScoreSource;
 use sieve::compiler::grammar as sieve_grammar;
 
-pub(crate) fn generate_require_spamtest(ctx: &mut CodeGen) -> Result<(), BoxError> {
-    ctx.buffer.write(
-        r#"
-        async function scoreEmail() {
-          const response = await env.AI.run(
-            "@cf/huggingface/distilbert-sst-2-int8",
-            {
-              text: raw
-            }
-          );
-        }
-        "#,
-    );
+/// Classifies the message once per test kind, returns a score between 0 and 1
+/// or null when the message couldn't be classified.
+const SIEVE_SCORE: &str = r#"
+const sieveScores = new WeakMap();
+
+function sieveScore(name, source, context) {
+  const scores = sieveScores.get(context.message) ?? new Map();
+  sieveScores.set(context.message, scores);
+  if (!scores.has(name)) {
+    scores.set(name, sieveClassify(source, context));
+  }
+  return scores.get(name);
+}
+
+async function sieveClassify(source, context) {
+  let score;
+  switch (source.type) {
+    case "header": {
+      // Scanners also write their verdict as a word.
+      const value = context.message.headers.get(source.name) ?? "";
+      if (/^\s*(yes|true|infected)\b/i.test(value)) {
+        score = 1;
+      } else if (/^\s*(no|false|clean)\b/i.test(value)) {
+        score = 0;
+      } else {
+        score = parseFloat(value) / source.max;
+      }
+      break;
+    }
+    case "ai": {
+      const { subject = "", text = "" } = context.parsedMessage;
+      const labels = await context.env[source.binding].run(source.model, {
+        text: `${subject}\n${text}`.slice(0, 4096),
+      });
+      const label = labels.find((label) => label.label.toUpperCase() === source.label.toUpperCase());
+      score = label === undefined ? NaN : label.score;
+      break;
+    }
+    case "binding": {
+      const response = await context.env[source.binding].fetch("https://sieve2workers/score", {
+        method: "POST",
+        body: context.raw,
+      });
+      score = response.ok ? parseFloat(await response.text()) : NaN;
+      break;
+    }
+    default:
+      score = await source.classify(context);
+  }
+  return typeof score !== "number" || Number.isNaN(score) ? null : Math.min(Math.max(score, 0), 1);
+}
+"#;
+
+/// The values of the test: "0" for a message that wasn't tested, then from
+/// "1" (not spam) to "10" (definitely spam). With :percent, a percentage and
+/// no value at all for a message that wasn't tested (RFC 5235, section 3.2).
+const SIEVE_SPAMTEST: &str = r#"
+async function sieveSpamTest(source, context, percent) {
+  const score = await sieveScore("spamtest", source, context);
+  if (score === null) {
+    return percent ? [] : ["0"];
+  }
+  return [String(percent ? Math.round(score * 100) : 1 + Math.round(score * 9))];
+}
+"#;
 
-    Ok(())
+/// "0" for a message that wasn't tested, "1" when no virus was found, "4" when
+/// the message possibly contains one and "5" when it definitely does.
+const SIEVE_VIRUSTEST: &str = r#"
+async function sieveVirusTest(source, context) {
+  const score = await sieveScore("virustest", source, context);
+  if (score === null) {
+    return "0";
+  }
+  return score === 0 ? "1" : score < 1 ? "4" : "5";
 }
+"#;
 
 pub(crate) fn generate_test_spamtest(
     ctx: &mut CodeGen,
     node: &sieve_grammar::tests::test_spamtest::TestSpamTest,
 ) -> Result<(), BoxError> {
-    ctx.buffer.write("(await scoreEmail()) == 37");
+    let source = ctx.opts.spamtest.clone();
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_values_match(
+            ctx,
+            &node.match_type,
+            &node.comparator,
+            std::slice::from_ref(&node.value),
+            |ctx| {
+                match &source {
+                    Some(source) => {
+                        ctx.add_helper(SIEVE_SCORE);
+                        ctx.add_helper(SIEVE_SPAMTEST);
+                        ctx.buffer.write("(await sieveSpamTest(");
+                        generate_score_source(ctx, source, "sieveSpamTestClassify");
+                        ctx.buffer.write(&format!(
+                            ", {{ message, env, raw, parsedMessage }}, {}))",
+                            node.percent
+                        ));
+                    }
+                    None if node.percent => ctx.buffer.write("[]"),
+                    None => ctx.buffer.write("[\"0\"]"),
+                }
+                Ok(())
+            },
+        )
+    })
+}
+
+pub(crate) fn generate_test_virustest(
+    ctx: &mut CodeGen,
+    node: &mirror::TestVirusTest,
+) -> Result<(), BoxError> {
+    let source = ctx.opts.virustest.clone();
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_values_match(
+            ctx,
+            &node.match_type,
+            &node.comparator,
+            std::slice::from_ref(&node.value),
+            |ctx| {
+                match &source {
+                    Some(source) => {
+                        ctx.add_helper(SIEVE_SCORE);
+                        ctx.add_helper(SIEVE_VIRUSTEST);
+                        ctx.buffer.write("[await sieveVirusTest(");
+                        generate_score_source(ctx, source, "sieveVirusTestClassify");
+                        ctx.buffer.write(", { message, env, raw, parsedMessage })]");
+                    }
+                    None => ctx.buffer.write("[\"0\"]"),
+                }
+                Ok(())
+            },
+        )
+    })
+}
 
-    Ok(())
+/// Generates the object literal describing `source` to `sieveScore`, a module
+/// is imported as `import_name`.
+pub(crate) fn generate_score_source(ctx: &mut CodeGen, source: &ScoreSource, import_name: &str) {
+    match source {
+        ScoreSource::Header { name, max } => {
+            ctx.buffer.write("{ type: \"header\", name: ");
+            ctx.buffer.write_quoted(name);
+            ctx.buffer.write(&format!(", max: {max} }}"));
+        }
+        ScoreSource::WorkersAi {
+            binding,
+            model,
+            label,
+        } => {
+            ctx.buffer.write("{ type: \"ai\", binding: ");
+            ctx.buffer.write_quoted(binding);
+            ctx.buffer.write(", model: ");
+            ctx.buffer.write_quoted(model);
+            ctx.buffer.write(", label: ");
+            ctx.buffer.write_quoted(label);
+            ctx.buffer.write(" }");
+        }
+        ScoreSource::Binding(binding) => {
+            ctx.buffer.write("{ type: \"binding\", binding: ");
+            ctx.buffer.write_quoted(binding);
+            ctx.buffer.write(" }");
+        }
+        ScoreSource::Module(path) => {
+            ctx.add_import(format!(
+                "import {import_name} from \"{}\";\n",
+                escape_js_string(path, '"')
+            ));
+            ctx.buffer
+                .write(&format!("{{ type: \"module\", classify: {import_name} }}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_test_spamtest() {
+        let header = ScoreSource::Header {
+            name: "X-Spam-Score".to_owned(),
+            max: 10.0,
+        };
+        let test_cases = &[
+            (
+                r#"require ["spamtest", "relational", "comparator-i;ascii-numeric"];
+                if spamtest :value "ge" :comparator "i;ascii-numeric" "5" { discard; }"#,
+                Some(header.clone()),
+                r#"(await sieveSpamTest({ type: "header", name: "X-Spam-Score", max: 10 }, { message, env, raw, parsedMessage }, false)).some((value) => sieveRelational(value, "5", "ge", "i;ascii-numeric"))"#,
+            ),
+            (
+                r#"require ["spamtestplus", "relational", "comparator-i;ascii-numeric"];
+                if not spamtest :percent :value "gt" :comparator "i;ascii-numeric" "50" { stop; }"#,
+                Some(ScoreSource::WorkersAi {
+                    binding: "AI".to_owned(),
+                    model: "@cf/example/spam".to_owned(),
+                    label: "SPAM".to_owned(),
+                }),
+                r#"!((await sieveSpamTest({ type: "ai", binding: "AI", model: "@cf/example/spam", label: "SPAM" }, { message, env, raw, parsedMessage }, true)).some((value) => sieveRelational(value, "50", "gt", "i;ascii-numeric")))"#,
+            ),
+            (
+                r#"require "spamtest"; if spamtest "0" { stop; }"#,
+                Some(ScoreSource::Module("./score.js".to_owned())),
+                r#"(await sieveSpamTest({ type: "module", classify: sieveSpamTestClassify }, { message, env, raw, parsedMessage }, false)).some((value) => sieveIs(value, "0", "i;ascii-casemap"))"#,
+            ),
+            (
+                r#"require "spamtest"; if spamtest "0" { stop; }"#,
+                None,
+                r#"["0"].some((value) => sieveIs(value, "0", "i;ascii-casemap"))"#,
+            ),
+            (
+                r#"require ["spamtestplus", "relational", "comparator-i;ascii-numeric"];
+                if spamtest :percent :count "eq" :comparator "i;ascii-numeric" "0" { stop; }"#,
+                None,
+                r#"sieveCount([], ["0",], "eq", "i;ascii-numeric")"#,
+            ),
+        ];
+
+        for (input, source, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                spamtest: source.clone(),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+            assert_eq!(
+                js.starts_with("import sieveSpamTestClassify from \"./score.js\";\n"),
+                matches!(source, Some(ScoreSource::Module(_)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_generate_test_virustest() {
+        let test_cases = &[
+            (
+                r#"require ["virustest", "relational", "comparator-i;ascii-numeric"];
+                if virustest :value "ge" :comparator "i;ascii-numeric" "4" { discard; }"#,
+                Some(ScoreSource::Header {
+                    name: "X-Virus-Status".to_owned(),
+                    max: 1.0,
+                }),
+                r#"[await sieveVirusTest({ type: "header", name: "X-Virus-Status", max: 1 }, { message, env, raw, parsedMessage })].some((value) => sieveRelational(value, "4", "ge", "i;ascii-numeric"))"#,
+            ),
+            (
+                r#"require "virustest"; if not virustest "1" { stop; }"#,
+                Some(ScoreSource::Binding("SCANNER".to_owned())),
+                r#"!([await sieveVirusTest({ type: "binding", binding: "SCANNER" }, { message, env, raw, parsedMessage })].some((value) => sieveIs(value, "1", "i;ascii-casemap")))"#,
+            ),
+            (
+                r#"require "virustest"; if virustest "0" { stop; }"#,
+                None,
+                r#"["0"].some((value) => sieveIs(value, "0", "i;ascii-casemap"))"#,
+            ),
+        ];
+
+        for (input, source, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                virustest: source.clone(),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+        }
+    }
 }
@@ -1,177 +1,433 @@
-use crate::codegen::js::{body, generate_instr, generate_value, spamtest, BoxError, CodeGen};
+use crate::codegen::js::{
+    address, body, comparator, envelope, exists, generate_instr, generate_value, header, list,
+    mirror, regex, relational, size, spamtest, vacation, BoxError, CodeGen,
+};
 use sieve::compiler::grammar as sieve_grammar;
 use sieve::compiler::grammar::instruction::Instruction;
 
+/// Runs `regex` against `value` and, when it matches, replaces the match
+/// variables with its capture groups (RFC 5229, section 3.2).
+const SIEVE_MATCH: &str = r#"
+function sieveMatch(variables, value, regex) {
+  const match = regex.exec(value);
+  if (match === null) {
+    return false;
+  }
+  for (const name of Object.keys(variables)) {
+    if (name.startsWith("match")) {
+      delete variables[name];
+    }
+  }
+  match.forEach((capture, i) => {
+    variables["match" + i] = capture;
+  });
+  return true;
+}
+"#;
+
+/// A boolean expression reconstructed from the Jz/Jnz layout sieve-rs uses
+/// for `allof`/`anyof`. `not` is already pushed down onto each test by
+/// sieve-rs (De Morgan), so it only shows up as the tests' `is_not` flag.
+#[derive(Debug)]
+enum Condition<'a> {
+    Test(&'a sieve_grammar::test::Test),
+    AllOf(Vec<Condition<'a>>),
+    AnyOf(Vec<Condition<'a>>),
+}
+
+/// A test followed by the conditional jump sieve-rs emitted after it.
+struct Operand<'a> {
+    test: &'a sieve_grammar::test::Test,
+    jump_pos: usize,
+    jump_target: usize,
+    is_all: bool,
+}
+
 pub(crate) fn generate_test(
     ctx: &mut CodeGen,
     node: &sieve_grammar::test::Test,
 ) -> Result<(), BoxError> {
-    let jz = match ctx.eat() {
-        Instruction::Jz(jz) => *jz,
-        e => unreachable!("invalid Jump instruction: {e:?}"),
+    let instructions = ctx.instructions;
+
+    // Collect every test of the condition. Jumps emitted by a combinator
+    // always land on another jump of the same condition, the final Jz lands
+    // after the consequent.
+    let mut operands = vec![];
+    let mut test = node;
+    let jz = loop {
+        let jump_pos = ctx.cursor;
+        let (jump_target, is_all) = match ctx.eat() {
+            Instruction::Jz(target) => (*target, true),
+            Instruction::Jnz(target) => (*target, false),
+            e => return Err(format!("invalid jump instruction after test: {e:?}").into()),
+        };
+
+        operands.push(Operand {
+            test,
+            jump_pos,
+            jump_target,
+            is_all,
+        });
+
+        let is_inner = matches!(
+            instructions.get(jump_target),
+            Some(Instruction::Jz(_) | Instruction::Jnz(_))
+        );
+        if !is_inner {
+            if !is_all {
+                return Err(format!("invalid jump instruction at {jump_pos}").into());
+            }
+            break jump_target;
+        }
+
+        test = match ctx.eat() {
+            Instruction::Test(test) => test,
+            e => return Err(format!("expected test in condition, found: {e:?}").into()),
+        };
     };
 
+    let end = ctx.cursor - 1;
+    let condition = build_condition(&operands, end)?;
+
     ctx.buffer.write("if (");
+    generate_condition(ctx, &condition)?;
+    ctx.buffer.write(") {");
 
-    match node {
-        sieve_grammar::test::Test::Address(addr) => {
-            if addr.header_list.len() != 1 {
-                return Err(
-                    format!("unsupported header_list len: {}", addr.header_list.len()).into(),
-                );
-            }
-            if addr.key_list.len() != 1 {
-                return Err(format!("unsupported key_list len: {}", addr.key_list.len()).into());
-            }
-            if addr.match_type != sieve_grammar::MatchType::Is {
-                return Err(format!("unsupported match_type: {:?}", addr.match_type).into());
+    while ctx.cursor < jz {
+        // An `elsif`/`else` follows: the consequent ends with a jump over it.
+        if ctx.cursor == jz - 1 {
+            if let Instruction::Jmp(jmp) = instructions[ctx.cursor] {
+                ctx.eat();
+                ctx.buffer.write("} else {");
+
+                while ctx.cursor < jmp {
+                    let instr = ctx.eat();
+                    generate_instr(ctx, instr)?;
+                }
+                break;
             }
+        }
 
-            let header = addr.header_list.first().ok_or("expect one element")?;
+        let instr = ctx.eat();
+        generate_instr(ctx, instr)?;
+    }
 
-            match header {
-                sieve::compiler::Value::Text(s) => {
-                    if s.to_lowercase() != "to" {
-                        return Err(format!("unsupported header: {s}").into());
-                    }
+    ctx.buffer.write("}");
 
-                    ctx.buffer.write("parsedMessage.to[0].address");
-                }
+    Ok(())
+}
 
-                e => return Err(format!("address test for header not implemented: {e:?}").into()),
-            }
+/// Rebuilds the expression of the operands whose last jump is at `end`.
+///
+/// The separators of a combinator all jump to the end of the combinator.
+/// When a nested combinator is the last operand, its own separators jump to
+/// the same position, but they come after the ones of the enclosing
+/// combinator and switch the jump kind (a nested combinator of the same kind
+/// is equivalent to its flattened form).
+fn build_condition<'a>(operands: &[Operand<'a>], end: usize) -> Result<Condition<'a>, BoxError> {
+    let (last, rest) = operands.split_last().ok_or("empty condition")?;
+
+    let mut separators = rest
+        .iter()
+        .enumerate()
+        .filter(|(_, operand)| operand.jump_target == end)
+        .peekable();
+    let is_all = match separators.peek() {
+        Some((_, operand)) => operand.is_all,
+        None if rest.is_empty() => return Ok(Condition::Test(last.test)),
+        None => return Err(format!("invalid condition layout ending at {end}").into()),
+    };
+    let separators = separators
+        .take_while(|(_, operand)| operand.is_all == is_all)
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+
+    let mut children = vec![];
+    let mut start = 0;
+    for idx in separators {
+        children.push(build_condition(
+            &operands[start..=idx],
+            operands[idx].jump_pos,
+        )?);
+        start = idx + 1;
+    }
+    children.push(build_condition(&operands[start..], end)?);
 
-            ctx.buffer.write("===");
+    Ok(if is_all {
+        Condition::AllOf(children)
+    } else {
+        Condition::AnyOf(children)
+    })
+}
 
-            for item in &addr.key_list {
-                generate_value(ctx, &item)?;
-            }
+fn generate_condition(ctx: &mut CodeGen, condition: &Condition) -> Result<(), BoxError> {
+    let (children, operator) = match condition {
+        Condition::Test(test) => return generate_test_expr(ctx, test),
+        Condition::AllOf(children) => (children, " && "),
+        Condition::AnyOf(children) => (children, " || "),
+    };
+
+    ctx.buffer.write("(");
+    for (i, child) in children.iter().enumerate() {
+        if i > 0 {
+            ctx.buffer.write(operator);
         }
+        generate_condition(ctx, child)?;
+    }
+    ctx.buffer.write(")");
 
-        sieve_grammar::test::Test::Header(node) => {
-            if node.header_list.len() != 1 {
-                return Err(
-                    format!("unsupported header_list len: {}", node.header_list.len()).into(),
-                );
-            }
-            if node.key_list.len() != 1 {
-                return Err(format!("unsupported key_list len: {}", node.key_list.len()).into());
-            }
+    Ok(())
+}
 
-            let header = node.header_list.first().ok_or("expect one element")?;
-
-            match header {
-                sieve::compiler::Value::Text(v) => match &*v.to_lowercase() {
-                    "subject" => {
-                        ctx.buffer.write("parsedMessage.subject");
-                    }
-
-                    _ => {
-                        ctx.buffer.write("parsedMessage.headers[");
-                        generate_value(ctx, &header)?;
-                        ctx.buffer.write("].value");
-                    }
-                },
-
-                _ => {
-                    ctx.buffer.write("parsedMessage.headers[");
-                    generate_value(ctx, &header)?;
-                    ctx.buffer.write("].value");
-                }
-            }
+fn generate_test_expr(ctx: &mut CodeGen, node: &sieve_grammar::test::Test) -> Result<(), BoxError> {
+    match node {
+        sieve_grammar::test::Test::Address(n) => address::generate_test_address(ctx, n)?,
+        sieve_grammar::test::Test::Header(n) => header::generate_test_header(ctx, n)?,
+        sieve_grammar::test::Test::String(n) => generate_test_string(ctx, n)?,
+        sieve_grammar::test::Test::Body(n) => body::generate_test_body(ctx, n)?,
+        sieve_grammar::test::Test::SpamTest(n) => spamtest::generate_test_spamtest(ctx, n)?,
 
-            match node.match_type {
-                sieve_grammar::MatchType::Is => {
-                    ctx.buffer.write("===");
+        sieve_grammar::test::Test::True => {
+            ctx.buffer.write("true");
+        }
 
-                    for item in &node.key_list {
-                        generate_value(ctx, &item)?;
-                    }
-                }
+        sieve_grammar::test::Test::False => {
+            ctx.buffer.write("false");
+        }
 
-                sieve_grammar::MatchType::Contains => {
-                    ctx.buffer.write(".includes(");
+        // sieve-rs never negates it.
+        sieve_grammar::test::Test::Vacation(n) => vacation::generate_test_vacation(ctx, n)?,
 
-                    for item in &node.key_list {
-                        generate_value(ctx, &item)?;
-                    }
+        // The remaining tests can only be reached through their mirror.
+        node => match mirror::Test::try_from(node)? {
+            mirror::Test::Envelope(n) => envelope::generate_test_envelope(ctx, &n)?,
+            mirror::Test::Size(n) => size::generate_test_size(ctx, &n)?,
+            mirror::Test::Exists(n) => exists::generate_test_exists(ctx, &n)?,
+            mirror::Test::Virus(n) => spamtest::generate_test_virustest(ctx, &n)?,
+        },
+    }
 
-                    ctx.buffer.write(")");
-                }
+    Ok(())
+}
+
+/// Whether the test reads the message content, only the envelope and the
+/// size are known without buffering the message.
+pub(crate) fn needs_message_content(node: &sieve_grammar::test::Test) -> bool {
+    match node {
+        sieve_grammar::test::Test::True
+        | sieve_grammar::test::Test::False
+        | sieve_grammar::test::Test::String(_)
+        | sieve_grammar::test::Test::Vacation(_) => false,
+        node => !matches!(
+            mirror::Test::try_from(node),
+            Ok(mirror::Test::Envelope(_) | mirror::Test::Size(_))
+        ),
+    }
+}
+
+/// Generates the test expression written by `f`, negated when `is_not` is
+/// set. Every test generator must go through it so that `not` is never lost.
+pub(crate) fn generate_negatable<'a>(
+    ctx: &mut CodeGen<'a>,
+    is_not: bool,
+    f: impl FnOnce(&mut CodeGen<'a>) -> Result<(), BoxError>,
+) -> Result<(), BoxError> {
+    if is_not {
+        ctx.buffer.write("!(");
+    }
 
-                sieve_grammar::MatchType::Matches(_) => {
-                    ctx.buffer.write(".match(");
+    f(ctx)?;
 
-                    let key = node.key_list.first().ok_or("expect one element")?;
-                    if let sieve::compiler::Value::Text(s) = key {
-                        ctx.buffer.write("/");
-                        ctx.buffer.write(&sieve_to_js_regex(&s));
-                        ctx.buffer.write("/");
-                    };
+    if is_not {
+        ctx.buffer.write(")");
+    }
 
-                    ctx.buffer.write(")");
+    Ok(())
+}
+
+fn generate_test_string(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::tests::test_string::TestString,
+) -> Result<(), BoxError> {
+    let is_count = matches!(
+        mirror::Relational::try_from(&node.match_type),
+        Ok(mirror::Relational::Count(_))
+    );
+
+    generate_negatable(ctx, node.is_not, |ctx| {
+        generate_values_match(
+            ctx,
+            &node.match_type,
+            &node.comparator,
+            &node.key_list,
+            |ctx| {
+                ctx.buffer.write("[");
+                for source in &node.source {
+                    generate_value(ctx, source)?;
+                    ctx.buffer.write(",");
+                }
+                ctx.buffer.write("]");
+                // Empty strings aren't counted (RFC 5229, section 5).
+                if is_count {
+                    ctx.buffer.write(".filter((value) => value !== \"\")");
                 }
+                Ok(())
+            },
+        )
+    })
+}
 
-                e => todo!("match type: {e:?}"),
-            }
-        }
+/// Generates a comparison of the values of the JavaScript array written by
+/// `values` against any of the `keys`.
+pub(crate) fn generate_values_match<'a>(
+    ctx: &mut CodeGen<'a>,
+    match_type: &sieve_grammar::MatchType,
+    comparator: &sieve_grammar::Comparator,
+    keys: &[sieve::compiler::Value],
+    values: impl FnOnce(&mut CodeGen<'a>) -> Result<(), BoxError>,
+) -> Result<(), BoxError> {
+    // :count compares the number of values, not the values themselves.
+    if let Ok(mirror::Relational::Count(operator)) = mirror::Relational::try_from(match_type) {
+        return relational::generate_count_match(ctx, operator, comparator, keys, values);
+    }
+    // :list looks the values up in external lists.
+    if *match_type == sieve_grammar::MatchType::List {
+        return list::generate_list_match(ctx, keys, values);
+    }
 
-        sieve_grammar::test::Test::Vacation(_node) => {
-            // FIXME: for now there's no test for Vacation, we just execute the
-            // rule.
-            ctx.buffer.write("true");
-        }
+    values(ctx)?;
+    ctx.buffer.write(".some((value) => ");
+    generate_match_any(ctx, match_type, comparator, "value", keys)?;
+    ctx.buffer.write(")");
 
-        sieve_grammar::test::Test::String(node) => {
-            if node.match_type != sieve_grammar::MatchType::Is {
-                return Err(format!("unsupported match_type: {:?}", node.match_type).into());
-            }
-            if node.comparator != sieve_grammar::Comparator::AsciiCaseMap {
-                return Err(format!("unsupported comparator: {:?}", node.comparator).into());
-            }
+    Ok(())
+}
+
+/// Generates a comparison of the JavaScript expression `value` against any of
+/// the `keys`.
+pub(crate) fn generate_match_any(
+    ctx: &mut CodeGen,
+    match_type: &sieve_grammar::MatchType,
+    comparator: &sieve_grammar::Comparator,
+    value: &str,
+    keys: &[sieve::compiler::Value],
+) -> Result<(), BoxError> {
+    if keys.len() > 1 {
+        ctx.buffer.write("(");
+    }
 
-            let source = node.source.first().ok_or("expect one element")?;
-            generate_value(ctx, source)?;
+    for (i, key) in keys.iter().enumerate() {
+        if i > 0 {
+            ctx.buffer.write(" || ");
+        }
+        generate_match(ctx, match_type, comparator, value, key)?;
+    }
+
+    if keys.len() > 1 {
+        ctx.buffer.write(")");
+    }
 
-            ctx.buffer.write("===");
+    Ok(())
+}
 
-            let key = node.key_list.first().ok_or("expect one element")?;
-            generate_value(ctx, key)?;
+/// Generates a comparison of the JavaScript expression `value` against `key`.
+pub(crate) fn generate_match(
+    ctx: &mut CodeGen,
+    match_type: &sieve_grammar::MatchType,
+    comparator: &sieve_grammar::Comparator,
+    value: &str,
+    key: &sieve::compiler::Value,
+) -> Result<(), BoxError> {
+    match mirror::Relational::try_from(match_type) {
+        Ok(mirror::Relational::Value(operator)) => {
+            return relational::generate_value_match(ctx, operator, comparator, value, key);
+        }
+        Ok(mirror::Relational::Count(_)) => {
+            return Err(":count needs every value, use generate_values_match".into());
         }
+        Err(_) => {}
+    }
 
-        sieve_grammar::test::Test::Body(n) => {
-            body::generate_test_body(ctx, n)?;
+    match match_type {
+        sieve_grammar::MatchType::Is => {
+            comparator::generate_is(ctx, comparator, value, key)?;
         }
 
-        sieve_grammar::test::Test::SpamTest(n) => {
-            spamtest::generate_test_spamtest(ctx, n)?;
+        sieve_grammar::MatchType::Contains => {
+            comparator::generate_contains(ctx, comparator, value, key)?;
         }
 
-        e => todo!("test not implemented {:?}", e),
-    };
+        sieve_grammar::MatchType::Matches(positions)
+        | sieve_grammar::MatchType::Regex(positions) => {
+            // The match variables are only captured when the script uses them.
+            let capture = *positions != 0;
+            if capture {
+                ctx.add_helper(SIEVE_MATCH);
+                ctx.buffer
+                    .write(&format!("sieveMatch(variables, {value}, "));
+            }
 
-    ctx.buffer.write(") {");
+            match (match_type, key) {
+                (sieve_grammar::MatchType::Matches(_), sieve::compiler::Value::Text(s)) => {
+                    let regex = comparator::sieve_to_js_regex(s, comparator, capture)?;
+                    ctx.buffer.write(&regex);
+                }
+                (sieve_grammar::MatchType::Regex(_), sieve::compiler::Value::Regex(r)) => {
+                    let regex = regex::posix_to_js_regex(&r.expr, comparator)?;
+                    ctx.buffer.write(&regex);
+                }
+                (sieve_grammar::MatchType::Regex(_), sieve::compiler::Value::Text(s)) => {
+                    let regex = regex::posix_to_js_regex(s, comparator)?;
+                    ctx.buffer.write(&regex);
+                }
+                // A pattern with variables is only known at runtime.
+                (
+                    sieve_grammar::MatchType::Matches(_),
+                    sieve::compiler::Value::Variable(_) | sieve::compiler::Value::List(_),
+                ) => {
+                    comparator::generate_wildcard_regex(ctx, comparator, key, capture)?;
+                }
+                (
+                    sieve_grammar::MatchType::Regex(_),
+                    sieve::compiler::Value::Variable(_) | sieve::compiler::Value::List(_),
+                ) => {
+                    regex::generate_posix_regex(ctx, comparator, key)?;
+                }
+                (_, e) => return Err(format!("unsupported {match_type:?} key: {e:?}").into()),
+            }
 
-    while ctx.cursor < jz {
-        let instr = ctx.eat();
-        generate_instr(ctx, instr)?;
-    }
+            if capture {
+                ctx.buffer.write(")");
+            } else {
+                ctx.buffer.write(&format!(".test({value})"));
+            }
+        }
 
-    ctx.buffer.write("}");
+        e => return Err(format!("unsupported match_type: {e:?}").into()),
+    }
 
     Ok(())
 }
 
-fn sieve_to_js_regex(v: &str) -> String {
-    v.replace("*", ".*")
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::codegen::GenerateOpts;
     use std::sync::Arc;
 
+    /// Compiles `script` and generates its first test.
+    fn generate_script_test(script: &str) -> Result<String, BoxError> {
+        let script = sieve::Compiler::new().compile(script.as_bytes()).unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+
+        loop {
+            if let Instruction::Test(test) = ctx.eat() {
+                generate_test(&mut ctx, test)?;
+                return Ok(ctx.buffer.to_string());
+            }
+        }
+    }
+
     #[test]
     fn test_generate_test_address() {
         let test =
@@ -198,7 +454,7 @@ mod tests {
         generate_test(&mut ctx, &test).unwrap();
         assert_eq!(
             ctx.buffer.to_string(),
-            "if (parsedMessage.to[0].address===\"match\") {return;return;}"
+            "if (sieveAddresses(parsedMessage, [\"To\",]).map((address) => sieveAddressPart(address, \"all\")).some((value) => sieveIs(value, \"match\", \"i;ascii-casemap\"))) {return;return;}"
         );
     }
 
@@ -230,7 +486,7 @@ mod tests {
         generate_test(&mut ctx, &test).unwrap();
         assert_eq!(
             ctx.buffer.to_string(),
-            "if (parsedMessage.headers[\"x-header\"].value.includes(\"match\")) {return;return;}"
+            "if (sieveHeaderValues(parsedMessage, [\"x-header\",]).some((value) => sieveContains(value, \"match\", \"i;ascii-casemap\"))) {return;return;}"
         );
     }
 
@@ -260,7 +516,7 @@ mod tests {
         generate_test(&mut ctx, &test).unwrap();
         assert_eq!(
             ctx.buffer.to_string(),
-            "if (parsedMessage.subject.match(/.*/)) {return;return;}"
+            "if (sieveHeaderValues(parsedMessage, [\"subject\",]).some((value) => sieveMatch(variables, value, /^(.*?)$/su))) {return;return;}"
         );
     }
 
@@ -290,7 +546,7 @@ mod tests {
         generate_test(&mut ctx, &test).unwrap();
         assert_eq!(
             ctx.buffer.to_string(),
-            "if (parsedMessage.subject.includes(\"match\")) {return;return;}"
+            "if (sieveHeaderValues(parsedMessage, [\"SuBJecT\",]).some((value) => sieveContains(value, \"match\", \"i;ascii-casemap\"))) {return;return;}"
         );
     }
 
@@ -318,7 +574,159 @@ mod tests {
         generate_test(&mut ctx, &test).unwrap();
         assert_eq!(
             ctx.buffer.to_string(),
-            "if (\"test\"===\"Y\") {return;return;}"
+            "if ([\"test\",].some((value) => sieveIs(value, \"Y\", \"i;ascii-casemap\"))) {return;return;}"
+        );
+    }
+
+    #[test]
+    fn test_generate_test_combinators() {
+        let test_cases = &[
+            (
+                r#"if allof(header :is "a" "x", header :is "b" "y") { stop; }"#,
+                r#"if ((sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap")) && sieveHeaderValues(parsedMessage, ["b",]).some((value) => sieveIs(value, "y", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"if anyof(header :is "a" "x", header :is "b" "y") { stop; }"#,
+                r#"if ((sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap")) || sieveHeaderValues(parsedMessage, ["b",]).some((value) => sieveIs(value, "y", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"if not header :is "a" "x" { stop; }"#,
+                r#"if (!(sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"if not allof(header :is "a" "x", true) { stop; }"#,
+                r#"if ((!(sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap"))) || false)) {return;}"#,
+            ),
+            (
+                r#"if allof(anyof(header :is "a" "x", header :is "b" "y"), header :is "c" "z") { stop; }"#,
+                r#"if (((sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap")) || sieveHeaderValues(parsedMessage, ["b",]).some((value) => sieveIs(value, "y", "i;ascii-casemap"))) && sieveHeaderValues(parsedMessage, ["c",]).some((value) => sieveIs(value, "z", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"if anyof(header :is "a" "x", allof(header :is "b" "y", header :is "c" "z")) { stop; }"#,
+                r#"if ((sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap")) || (sieveHeaderValues(parsedMessage, ["b",]).some((value) => sieveIs(value, "y", "i;ascii-casemap")) && sieveHeaderValues(parsedMessage, ["c",]).some((value) => sieveIs(value, "z", "i;ascii-casemap"))))) {return;}"#,
+            ),
+            (
+                r#"if allof(anyof(header :is "a" "x", header :is "b" "y"), anyof(header :is "c" "z", allof(header :is "d" "w", not header :is "e" "v"))) { stop; }"#,
+                r#"if (((sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap")) || sieveHeaderValues(parsedMessage, ["b",]).some((value) => sieveIs(value, "y", "i;ascii-casemap"))) && (sieveHeaderValues(parsedMessage, ["c",]).some((value) => sieveIs(value, "z", "i;ascii-casemap")) || (sieveHeaderValues(parsedMessage, ["d",]).some((value) => sieveIs(value, "w", "i;ascii-casemap")) && !(sieveHeaderValues(parsedMessage, ["e",]).some((value) => sieveIs(value, "v", "i;ascii-casemap"))))))) {return;}"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(&generate_script_test(input).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_generate_test_else() {
+        assert_eq!(
+            generate_script_test(
+                r#"if header :is "a" "x" { stop; } elsif true { discard; } else { stop; }"#
+            )
+            .unwrap(),
+            "if (sieveHeaderValues(parsedMessage, [\"a\",]).some((value) => sieveIs(value, \"x\", \"i;ascii-casemap\"))) {return;} else {\ntry {if (true) {// discard the email\nimplicitKeep = false;} else {return;}\n} catch (err) {\nconsole.error('rule 9 failed and has been skipped', err);failed = true;}}"
         );
     }
+
+    #[test]
+    fn test_generate_test_not() {
+        let test_cases = &[
+            (
+                r#"if not address :is "to" "a@b.c" { stop; }"#,
+                r#"if (!(sieveAddresses(parsedMessage, ["to",]).map((address) => sieveAddressPart(address, "all")).some((value) => sieveIs(value, "a@b.c", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"if not header :matches "subject" "*" { stop; }"#,
+                r#"if (!(sieveHeaderValues(parsedMessage, ["subject",]).some((value) => /^.*$/su.test(value)))) {return;}"#,
+            ),
+            (
+                r#"require "variables"; if not string :is "a" "b" { stop; }"#,
+                r#"if (!(["a",].some((value) => sieveIs(value, "b", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"require "body"; if not body :text :contains "a" { stop; }"#,
+                r#"if (!(sieveTextBody(parsedMessage).some((value) => sieveContains(value, "a", "i;ascii-casemap")))) {return;}"#,
+            ),
+            (
+                r#"if not not header :is "a" "x" { stop; }"#,
+                r#"if (sieveHeaderValues(parsedMessage, ["a",]).some((value) => sieveIs(value, "x", "i;ascii-casemap"))) {return;}"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(&generate_script_test(input).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_generate_match_variable_key() {
+        let test_cases = &[
+            (
+                r#"require "variables"; set "x" "foo"; if header :matches "Subject" "${x}*" { stop; }"#,
+                r#".some((value) => sieveWildcardRegex(`${variables["local0"] ?? ""}*`, true, false).test(value))"#,
+            ),
+            (
+                r#"require "variables"; set "x" "foo"; if header :matches "Subject" "${x}*" { set "y" "${1}"; }"#,
+                r#".some((value) => sieveMatch(variables, value, sieveWildcardRegex(`${variables["local0"] ?? ""}*`, true, true)))"#,
+            ),
+            (
+                r#"require ["variables", "regex"]; set "x" "foo"; if header :regex :comparator "i;octet" "Subject" "${x}*" { stop; }"#,
+                r#".some((value) => sievePosixRegex(`${variables["local0"] ?? ""}*`, false).test(value))"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+            assert!(js.contains("function sieveRegexChar("), "{js}");
+        }
+    }
+
+    #[test]
+    fn test_generate_match_variables() {
+        let test_cases = &[
+            (
+                sieve_grammar::MatchType::Matches(0b10),
+                "[*] *",
+                r#"sieveMatch(variables, value, /^\[(.*?)\] (.*?)$/su)"#,
+            ),
+            (
+                sieve_grammar::MatchType::Matches(0b1),
+                "*@?*",
+                r#"sieveMatch(variables, value, /^(.*?)@(.)(.*?)$/su)"#,
+            ),
+            (
+                sieve_grammar::MatchType::Matches(0),
+                "*@?*",
+                r#"/^.*@..*$/su.test(value)"#,
+            ),
+        ];
+
+        for (match_type, key, expected) in test_cases {
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+            let key = sieve::compiler::Value::Text(Arc::new(key.to_string()));
+
+            generate_match(
+                &mut ctx,
+                match_type,
+                &sieve_grammar::Comparator::AsciiCaseMap,
+                "value",
+                &key,
+            )
+            .unwrap();
+            assert_eq!(&ctx.buffer.to_string(), expected);
+            assert_eq!(
+                ctx.helpers.contains(&SIEVE_MATCH),
+                *match_type != sieve_grammar::MatchType::Matches(0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_generate_test_not_implemented() {
+        generate_script_test(r#"require "mailbox"; if not mailboxexists "INBOX" { stop; }"#)
+            .unwrap_err();
+    }
 }
@@ -1,54 +1,356 @@
-use crate::codegen::js::{BoxError, CodeGen};
+//! Vacation (RFC 5230) and vacation-seconds (RFC 6131).
+
+use crate::codegen::buffer::escape_js_string;
+use crate::codegen::js::{generate_value, mirror, BoxError, CodeGen};
 use sieve::compiler::grammar as sieve_grammar;
+use sieve::compiler::grammar::instruction::Instruction;
+use sieve_grammar::actions::action_vacation::Period;
 
-pub(crate) fn generate_vacation(
+/// Whether a vacation response should be sent (RFC 5230, section 4). Nothing
+/// is sent to automated senders or mailing lists, or to the same sender twice
+/// for the same handle within the period, which is tracked in KV once the
+/// response is sent.
+const SIEVE_VACATION: &str = r#"
+const sieveVacationResponses = new WeakMap();
+
+async function sieveVacation(message, env, binding, { handle, seconds, addresses }) {
+  const sender = (message.from ?? "").toLowerCase();
+  const at = sender.lastIndexOf("@");
+  const localpart = at === -1 ? sender : sender.slice(0, at);
+  if (
+    sender === "" ||
+    ["mailer-daemon", "listserv", "majordomo"].includes(localpart) ||
+    localpart.startsWith("owner-") ||
+    localpart.endsWith("-request")
+  ) {
+    return false;
+  }
+  const own = [message.to, ...addresses].map((address) => address.toLowerCase());
+  if (own.includes(sender)) {
+    return false;
+  }
+  // Only messages addressed to the user get a response (section 4.5).
+  const recipients = ["to", "cc", "bcc", "resent-to", "resent-cc", "resent-bcc"]
+    .map((name) => message.headers.get(name))
+    .filter((value) => value !== null)
+    .flatMap((value) => addressParser(value))
+    .flatMap((address) => address.group || [address])
+    .map((address) => (address.address || "").toLowerCase());
+  if (!own.some((address) => recipients.includes(address))) {
+    return false;
+  }
+  const autoSubmitted = message.headers.get("Auto-Submitted");
+  if (autoSubmitted !== null && autoSubmitted.trim().toLowerCase() !== "no") {
+    return false;
+  }
+  if (/^\s*(bulk|list|junk)\s*$/i.test(message.headers.get("Precedence") ?? "")) {
+    return false;
+  }
+  for (const [name] of message.headers) {
+    if (name.toLowerCase().startsWith("list-")) {
+      return false;
+    }
+  }
+  if (seconds > 0) {
+    const digest = await crypto.subtle.digest("SHA-256", new TextEncoder().encode(handle));
+    const hash = [...new Uint8Array(digest)].map((byte) => byte.toString(16).padStart(2, "0")).join("");
+    const key = `vacation:${hash}:${sender}`;
+    const until = await env[binding].get(key);
+    if (until !== null && Number(until) > Date.now()) {
+      return false;
+    }
+    sieveVacationResponses.set(message, { kv: env[binding], key, seconds });
+  }
+  return true;
+}
+"#;
+
+/// Encodes a header value as an RFC 2047 word when it isn't plain ASCII, line
+/// breaks are removed so that interpolated values can't add headers.
+const SIEVE_ENCODE_HEADER: &str = r#"
+function sieveEncodeHeader(value) {
+  value = String(value).replace(/[\r\n]+/g, " ");
+  if (/^[\x20-\x7e]*$/.test(value)) {
+    return value;
+  }
+  const bytes = new TextEncoder().encode(value);
+  return `=?UTF-8?B?${btoa(Array.from(bytes, (byte) => String.fromCharCode(byte)).join(""))}?=`;
+}
+"#;
+
+/// Sends the vacation response, then records it. A :mime reason is a whole
+/// MIME entity, its headers included, otherwise it's the text of the response
+/// (RFC 5230, section 5). :from can have a display name, only the address is
+/// used for the envelope and the Message-ID.
+const SIEVE_VACATION_REPLY: &str = r#"
+async function sieveVacationReply(message, { from, subject, reason, mime }) {
+  const [mailbox = {}] = addressParser(String(from)).flatMap((address) => address.group || [address]);
+  const address = (mailbox.address || String(from)).replace(/[\r\n]+/g, "");
+  let sender = address;
+  if (mailbox.name) {
+    const name = /^[\x20-\x7e]*$/.test(mailbox.name)
+      ? `"${mailbox.name.replace(/["\\]/g, "\\$&")}"`
+      : sieveEncodeHeader(mailbox.name);
+    sender = `${name} <${address}>`;
+  }
+  const messageId = message.headers.get("Message-ID");
+  const headers = [
+    `From: ${sender}`,
+    `To: ${message.from}`,
+    `Subject: ${sieveEncodeHeader(subject ?? `Auto: ${message.headers.get("Subject") ?? ""}`)}`,
+    `Date: ${new Date().toUTCString()}`,
+    `Message-ID: <${crypto.randomUUID()}@${address.slice(address.lastIndexOf("@") + 1)}>`,
+    "Auto-Submitted: auto-replied",
+    "MIME-Version: 1.0",
+  ];
+  if (messageId !== null) {
+    headers.push(`In-Reply-To: ${messageId}`, `References: ${messageId}`);
+  }
+  let entity;
+  if (mime) {
+    entity = String(reason).replace(/\r?\n/g, "\r\n");
+  } else {
+    const bytes = new TextEncoder().encode(reason);
+    const body = btoa(Array.from(bytes, (byte) => String.fromCharCode(byte)).join(""));
+    entity = [
+      "Content-Type: text/plain; charset=utf-8",
+      "Content-Transfer-Encoding: base64",
+      "",
+      ...(body.match(/.{1,76}/g) ?? []),
+    ].join("\r\n");
+  }
+  await message.reply(new EmailMessage(address, message.from, `${headers.join("\r\n")}\r\n${entity}`));
+  const response = sieveVacationResponses.get(message);
+  if (response !== undefined) {
+    sieveVacationResponses.delete(message);
+    // KV doesn't expire keys sooner than a minute.
+    await response.kv.put(response.key, String(Date.now() + response.seconds * 1000), {
+      expirationTtl: Math.max(response.seconds, 60),
+    });
+  }
+}
+"#;
+
+/// The default period between two responses to the same sender, and the
+/// minimum one in days (RFC 5230, section 4.1).
+const DEFAULT_DAYS: u64 = 7;
+
+pub(crate) fn generate_test_vacation(
     ctx: &mut CodeGen,
-    node: &sieve_grammar::actions::action_vacation::Vacation,
+    node: &sieve_grammar::actions::action_vacation::TestVacation,
 ) -> Result<(), BoxError> {
-    let data = if let sieve::compiler::Value::Text(s) = &node.reason {
-        s
-    } else {
-        unimplemented!();
+    // :seconds 0 always responds (RFC 6131, section 2), there's nothing to
+    // track.
+    let seconds = match node.period {
+        Period::Days(days) => days.max(1) * 86400,
+        Period::Seconds(seconds) => seconds,
+        Period::Default => DEFAULT_DAYS * 86400,
     };
-
-    let subject = if let Some(sieve::compiler::Value::Text(s)) = &node.subject {
-        s
-    } else {
-        "Vacation auto-reply"
+    let binding = match &ctx.opts.vacation_kv {
+        Some(binding) => format!("\"{}\"", escape_js_string(binding, '"')),
+        None if seconds == 0 => "undefined".to_owned(),
+        None => {
+            return Err(
+                "vacation needs a KV namespace to track its responses, see --vacation-kv".into(),
+            )
+        }
     };
 
-    let from = ctx
-        .opts
-        .vacation_from_address
-        .as_ref()
-        .ok_or("Missing --vacation-from-address")?;
-
-    ctx.buffer
-        .write_prepend("import {createMimeMessage} from 'mimetext';");
-    ctx.buffer
-        .write_prepend("import { EmailMessage } from 'cloudflare:email';");
+    ctx.add_helper(SIEVE_VACATION);
 
     ctx.buffer.write(&format!(
-        r#"
-        const msg = createMimeMessage();
-        msg.setHeader("In-Reply-To", message.headers.get("Message-ID"));
-        msg.setSender({{ name: "Vacation auto-reply", addr: "{from}" }});
-        msg.setRecipient(message.from);
-        msg.setSubject("{subject}");
-        msg.addMessage({{
-          contentType: 'text/plain',
-          data: "{data}"
-        }});
-
-        const replyMessage = new EmailMessage(
-          "{from}",
-          message.from,
-          msg.asRaw()
-        );
-
-        await message.reply(replyMessage);
-        "#,
+        "await sieveVacation(message, env, {binding}, {{ handle: "
     ));
+    match &node.handle {
+        Some(handle) => generate_value(ctx, handle)?,
+        None => generate_default_handle(ctx, node)?,
+    }
+    ctx.buffer
+        .write(&format!(", seconds: {seconds}, addresses: ["));
+    for address in &node.addresses {
+        generate_value(ctx, address)?;
+        ctx.buffer.write(",");
+    }
+    if let Some(from) = &ctx.opts.vacation_from_address {
+        ctx.buffer.write_quoted(from);
+        ctx.buffer.write(",");
+    }
+    ctx.buffer.write("] })");
+
+    Ok(())
+}
+
+/// Without :handle, responses with the same arguments are the same (RFC 5230,
+/// section 4.2). They're the ones of the vacation action following the test.
+fn generate_default_handle(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::actions::action_vacation::TestVacation,
+) -> Result<(), BoxError> {
+    let action = ctx.instructions[ctx.cursor..]
+        .iter()
+        .find_map(|instr| match instr {
+            Instruction::Vacation(action) => Some(action),
+            _ => None,
+        })
+        .ok_or("vacation test without vacation action")?;
+
+    ctx.buffer.write("JSON.stringify([");
+    for value in [&action.subject, &action.from] {
+        match value {
+            Some(value) => generate_value(ctx, value)?,
+            None => ctx.buffer.write("null"),
+        }
+        ctx.buffer.write(", ");
+    }
+    ctx.buffer.write(&format!("{}, ", action.mime));
+    generate_value(ctx, &node.reason)?;
+    ctx.buffer.write("])");
 
     Ok(())
 }
+
+pub(crate) fn generate_vacation(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::actions::action_vacation::Vacation,
+) -> Result<(), BoxError> {
+    if mirror::VacationFcc::try_from(node)?.fcc.is_some() {
+        return Err("unsupported :fcc in vacation".into());
+    }
+
+    ctx.add_import("import { EmailMessage } from \"cloudflare:email\";\n".to_owned());
+    ctx.add_helper(SIEVE_ENCODE_HEADER);
+    ctx.add_helper(SIEVE_VACATION_REPLY);
+
+    if ctx.opts.debug {
+        ctx.buffer.write("console.log(\"vacation\");");
+    }
+
+    // The response comes from the recipient of the message by default.
+    ctx.buffer
+        .write("await sieveVacationReply(message, { from: ");
+    match (&node.from, &ctx.opts.vacation_from_address) {
+        (Some(from), _) => generate_value(ctx, from)?,
+        (None, Some(from)) => ctx.buffer.write_quoted(from),
+        (None, None) => ctx.buffer.write("message.to"),
+    }
+    ctx.buffer.write(", subject: ");
+    match &node.subject {
+        Some(subject) => generate_value(ctx, subject)?,
+        None => ctx.buffer.write("undefined"),
+    }
+    ctx.buffer.write(", reason: ");
+    generate_value(ctx, &node.reason)?;
+    ctx.buffer.write(&format!(", mime: {} }});", node.mime));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+
+    #[test]
+    fn test_generate_test_vacation() {
+        let test_cases = &[
+            (
+                r#"require "vacation"; vacation "I'm away";"#,
+                r#"if (await sieveVacation(message, env, "VACATION", { handle: JSON.stringify([null, null, false, "I'm away"]), seconds: 604800, addresses: ["me@example.com",] })) {"#,
+            ),
+            (
+                r#"require "vacation"; vacation :days 0 :handle "away" :addresses ["me@example.org"] "I'm away";"#,
+                r#"if (await sieveVacation(message, env, "VACATION", { handle: "away", seconds: 86400, addresses: ["me@example.org","me@example.com",] })) {"#,
+            ),
+            (
+                r#"require "vacation"; vacation :subject "Away" :from "me@example.org" "I'm away";"#,
+                r#"if (await sieveVacation(message, env, "VACATION", { handle: JSON.stringify(["Away", "me@example.org", false, "I'm away"]), seconds: 604800, addresses: ["me@example.com",] })) {"#,
+            ),
+            (
+                r#"require ["vacation", "vacation-seconds"]; vacation :seconds 3600 "I'm away";"#,
+                r#"if (await sieveVacation(message, env, "VACATION", { handle: JSON.stringify([null, null, false, "I'm away"]), seconds: 3600, addresses: ["me@example.com",] })) {"#,
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                vacation_from_address: Some("me@example.com".to_owned()),
+                vacation_kv: Some("VACATION".to_owned()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+        }
+    }
+
+    #[test]
+    fn test_generate_test_vacation_without_kv() {
+        let test_cases = &[
+            (r#"require "vacation"; vacation "I'm away";"#, false),
+            (
+                r#"require ["vacation", "vacation-seconds"]; vacation :seconds 0 "I'm away";"#,
+                true,
+            ),
+        ];
+
+        for (input, ok) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                vacation_from_address: Some("me@example.com".to_owned()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js();
+
+            assert_eq!(js.is_ok(), *ok, "{input}");
+            if let Ok(js) = js {
+                assert!(js.contains("await sieveVacation(message, env, undefined, { handle: "));
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_vacation() {
+        let test_cases = &[
+            (
+                r#"require "vacation"; vacation "I'm away";"#,
+                None,
+                r#"await sieveVacationReply(message, { from: message.to, subject: undefined, reason: "I'm away", mime: false });"#,
+            ),
+            (
+                r#"require "vacation"; vacation "I'm away";"#,
+                Some("me@example.com"),
+                r#"await sieveVacationReply(message, { from: "me@example.com", subject: undefined, reason: "I'm away", mime: false });"#,
+            ),
+            (
+                r#"require ["vacation", "variables"]; set "name" "Jane"; vacation :from "jane@example.com" :subject "Re: ${name}" :mime "Content-Type: text/plain
+
+${name} is away";"#,
+                Some("me@example.com"),
+                r#"await sieveVacationReply(message, { from: "jane@example.com", subject: `Re: ${variables["local0"] ?? ""}`, reason: `Content-Type: text/plain\u000a\u000a${variables["local0"] ?? ""} is away`, mime: true });"#,
+            ),
+            (
+                r#"require "vacation"; vacation :from "Jane Doe <jane@example.com>" "I'm away";"#,
+                None,
+                r#"await sieveVacationReply(message, { from: "Jane Doe <jane@example.com>", subject: undefined, reason: "I'm away", mime: false });"#,
+            ),
+        ];
+
+        for (input, from, expected) in test_cases {
+            let script = sieve::Compiler::new().compile(input.as_bytes()).unwrap();
+            let opts = GenerateOpts {
+                vacation_from_address: from.map(str::to_owned),
+                vacation_kv: Some("VACATION".to_owned()),
+                ..GenerateOpts::default()
+            };
+            let mut ctx = CodeGen::new(opts, &script.instructions);
+            let js = ctx.generate_js().unwrap();
+
+            assert!(js.contains(expected), "{js}");
+            assert!(js.starts_with("import { EmailMessage } from \"cloudflare:email\";\n"));
+            assert!(js.contains("new EmailMessage(address, message.from, "));
+        }
+    }
+}
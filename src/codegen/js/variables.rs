@@ -0,0 +1,332 @@
+use crate::codegen::js::{generate_value, BoxError, CodeGen};
+use sieve::compiler::grammar as sieve_grammar;
+use sieve::compiler::grammar::actions::action_set::Modifier;
+
+const SIEVE_LOWER_FIRST: &str = r#"
+function sieveLowerFirst(value) {
+  const [first = "", ...rest] = String(value);
+  return first.toLowerCase() + rest.join("");
+}
+"#;
+
+const SIEVE_UPPER_FIRST: &str = r#"
+function sieveUpperFirst(value) {
+  const [first = "", ...rest] = String(value);
+  return first.toUpperCase() + rest.join("");
+}
+"#;
+
+/// Percent-encodes everything but the unreserved characters (RFC 5435,
+/// section 4.1), `encodeURIComponent` leaves a few more alone.
+const SIEVE_ENCODE_URL: &str = r#"
+function sieveEncodeUrl(value) {
+  return encodeURIComponent(String(value)).replace(
+    /[!'()*]/g,
+    (c) => "%" + c.charCodeAt(0).toString(16).toUpperCase(),
+  );
+}
+"#;
+
+/// The environment items of RFC 5183, as seen from Email Workers.
+const SIEVE_ENVIRONMENT: &str = concat!(
+    r#"
+function sieveEnvironment(message, name) {
+  const domain = message.to.slice(message.to.lastIndexOf("@") + 1);
+  const items = {
+    domain,
+    host: domain,
+    location: "MTA",
+    name: "sieve2workers",
+    phase: "during",
+    version: ""#,
+    env!("CARGO_PKG_VERSION"),
+    r#"",
+  };
+  return items[name];
+}
+"#
+);
+
+/// Generates the lookup of a variable, `undefined` when it's not set.
+pub(crate) fn generate_variable(
+    ctx: &mut CodeGen,
+    node: &sieve::compiler::VariableType,
+) -> Result<(), BoxError> {
+    match node {
+        sieve::compiler::VariableType::Local(idx) => {
+            ctx.buffer.write("variables[");
+            ctx.buffer.write_quoted(&format!("local{idx}"));
+            ctx.buffer.write("]");
+        }
+        sieve::compiler::VariableType::Match(num) => {
+            ctx.buffer.write("variables[");
+            ctx.buffer.write_quoted(&format!("match{num}"));
+            ctx.buffer.write("]");
+        }
+        sieve::compiler::VariableType::Global(name) => {
+            ctx.buffer.write("globals[");
+            ctx.buffer.write_quoted(name);
+            ctx.buffer.write("]");
+        }
+        sieve::compiler::VariableType::Environment(name) => {
+            ctx.add_helper(SIEVE_ENVIRONMENT);
+            ctx.buffer.write("sieveEnvironment(message, ");
+            ctx.buffer.write_quoted(name);
+            ctx.buffer.write(")");
+        }
+        sieve::compiler::VariableType::Envelope(sieve::Envelope::From) => {
+            ctx.buffer.write("message.from");
+        }
+        sieve::compiler::VariableType::Envelope(sieve::Envelope::To) => {
+            ctx.buffer.write("message.to");
+        }
+        e => return Err(format!("variable type not implemented: {e:?}").into()),
+    }
+
+    Ok(())
+}
+
+pub(crate) fn generate_clear(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::Clear,
+) -> Result<(), BoxError> {
+    // The local variables declared in the block that ends here.
+    for idx in node.local_vars_idx..node.local_vars_idx + node.local_vars_num {
+        ctx.buffer.write("delete variables[");
+        ctx.buffer.write_quoted(&format!("local{idx}"));
+        ctx.buffer.write("];");
+    }
+
+    // The match variables used by the block that ends here.
+    for num in 0..u64::BITS {
+        if node.match_vars & (1 << num) != 0 {
+            ctx.buffer.write("delete variables[");
+            ctx.buffer.write_quoted(&format!("match{num}"));
+            ctx.buffer.write("];");
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn generate_set(
+    ctx: &mut CodeGen,
+    node: &sieve_grammar::actions::action_set::Set,
+) -> Result<(), BoxError> {
+    // sieve-rs already sorts them, but the modifiers must be applied from the
+    // highest precedence to the lowest (RFC 5229, section 4.1).
+    let mut modifiers = node.modifiers.iter().collect::<Vec<_>>();
+    modifiers.sort_by_key(|modifier| std::cmp::Reverse(modifier.order()));
+
+    let modifiers = modifiers
+        .into_iter()
+        .map(|modifier| generate_modifier(ctx, modifier))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match &node.name {
+        name @ (sieve::compiler::VariableType::Local(_)
+        | sieve::compiler::VariableType::Global(_)) => generate_variable(ctx, name)?,
+        e => return Err(format!("variable can't be set: {e:?}").into()),
+    }
+
+    ctx.buffer.write(" = ");
+
+    for (prefix, _) in modifiers.iter().rev() {
+        ctx.buffer.write(prefix);
+    }
+    generate_value(ctx, &node.value)?;
+    for (_, suffix) in &modifiers {
+        ctx.buffer.write(suffix);
+    }
+
+    ctx.buffer.write(";");
+    ctx.buffer.newline();
+    Ok(())
+}
+
+/// Returns the JavaScript wrapped around the value to apply the modifier.
+fn generate_modifier(
+    ctx: &mut CodeGen,
+    modifier: &Modifier,
+) -> Result<(&'static str, &'static str), BoxError> {
+    Ok(match modifier {
+        Modifier::Lower => ("String(", ").toLowerCase()"),
+        Modifier::Upper => ("String(", ").toUpperCase()"),
+        Modifier::LowerFirst => {
+            ctx.add_helper(SIEVE_LOWER_FIRST);
+            ("sieveLowerFirst(", ")")
+        }
+        Modifier::UpperFirst => {
+            ctx.add_helper(SIEVE_UPPER_FIRST);
+            ("sieveUpperFirst(", ")")
+        }
+        Modifier::QuoteWildcard => ("String(", r#").replace(/[*?\\]/g, "\\$&")"#),
+        Modifier::QuoteRegex => ("String(", r#").replace(/[\\^$.|?*+()[\]{}]/g, "\\$&")"#),
+        Modifier::EncodeUrl => {
+            ctx.add_helper(SIEVE_ENCODE_URL);
+            ("sieveEncodeUrl(", ")")
+        }
+        // The length in characters, not in UTF-16 code units.
+        Modifier::Length => ("String([...String(", ")].length)"),
+        e => return Err(format!("unsupported set modifier: {e:?}").into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::GenerateOpts;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_generate_set() {
+        let test_cases = &[
+            (vec![], r#"variables["local0"] = "Ab";"#),
+            (
+                vec![Modifier::Lower],
+                r#"variables["local0"] = String("Ab").toLowerCase();"#,
+            ),
+            (
+                vec![Modifier::Upper],
+                r#"variables["local0"] = String("Ab").toUpperCase();"#,
+            ),
+            (
+                vec![Modifier::LowerFirst],
+                r#"variables["local0"] = sieveLowerFirst("Ab");"#,
+            ),
+            (
+                vec![Modifier::UpperFirst],
+                r#"variables["local0"] = sieveUpperFirst("Ab");"#,
+            ),
+            (
+                vec![Modifier::QuoteWildcard],
+                r#"variables["local0"] = String("Ab").replace(/[*?\\]/g, "\\$&");"#,
+            ),
+            (
+                vec![Modifier::QuoteRegex],
+                r#"variables["local0"] = String("Ab").replace(/[\\^$.|?*+()[\]{}]/g, "\\$&");"#,
+            ),
+            (
+                vec![Modifier::EncodeUrl],
+                r#"variables["local0"] = sieveEncodeUrl("Ab");"#,
+            ),
+            (
+                vec![Modifier::Length],
+                r#"variables["local0"] = String([...String("Ab")].length);"#,
+            ),
+            // :length is applied last, :lower before :upperfirst.
+            (
+                vec![Modifier::Length, Modifier::UpperFirst, Modifier::Lower],
+                r#"variables["local0"] = String([...String(sieveUpperFirst(String("Ab").toLowerCase()))].length);"#,
+            ),
+            (
+                vec![
+                    Modifier::EncodeUrl,
+                    Modifier::QuoteWildcard,
+                    Modifier::Upper,
+                ],
+                r#"variables["local0"] = sieveEncodeUrl(String(String("Ab").toUpperCase()).replace(/[*?\\]/g, "\\$&"));"#,
+            ),
+            // :quoteregex is applied after :lower and :upper, whatever their
+            // order in the script.
+            (
+                vec![Modifier::QuoteRegex, Modifier::Lower],
+                r#"variables["local0"] = String(String("Ab").toLowerCase()).replace(/[\\^$.|?*+()[\]{}]/g, "\\$&");"#,
+            ),
+            (
+                vec![Modifier::Upper, Modifier::QuoteRegex],
+                r#"variables["local0"] = String(String("Ab").toUpperCase()).replace(/[\\^$.|?*+()[\]{}]/g, "\\$&");"#,
+            ),
+        ];
+
+        for (modifiers, expected) in test_cases {
+            let node = sieve_grammar::actions::action_set::Set {
+                modifiers: modifiers.clone(),
+                name: sieve::compiler::VariableType::Local(0),
+                value: sieve::compiler::Value::Text(Arc::new("Ab".to_owned())),
+            };
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_set(&mut ctx, &node).unwrap();
+            assert_eq!(ctx.buffer.to_string(), format!("{expected}\n"));
+        }
+    }
+
+    #[test]
+    fn test_generate_variable() {
+        let test_cases = &[
+            (
+                sieve::compiler::VariableType::Local(3),
+                r#"variables["local3"]"#,
+            ),
+            (
+                sieve::compiler::VariableType::Match(1),
+                r#"variables["match1"]"#,
+            ),
+            (
+                sieve::compiler::VariableType::Global("g".to_owned()),
+                r#"globals["g"]"#,
+            ),
+            (
+                sieve::compiler::VariableType::Environment("phase".to_owned()),
+                r#"sieveEnvironment(message, "phase")"#,
+            ),
+            (
+                sieve::compiler::VariableType::Envelope(sieve::Envelope::From),
+                "message.from",
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+
+            generate_variable(&mut ctx, input).unwrap();
+            assert_eq!(&ctx.buffer.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_generate_clear() {
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &[]);
+        let node = sieve_grammar::Clear {
+            local_vars_idx: 2,
+            local_vars_num: 2,
+            match_vars: 0b101,
+        };
+
+        generate_clear(&mut ctx, &node).unwrap();
+        assert_eq!(
+            ctx.buffer.to_string(),
+            r#"delete variables["local2"];delete variables["local3"];delete variables["match0"];delete variables["match2"];"#
+        );
+    }
+
+    #[test]
+    fn test_generate_set_global() {
+        let script = sieve::Compiler::new()
+            .compile(
+                br#"require ["variables", "include"]; global "count"; set "count" "1"; set "global.last" "${count}";"#,
+            )
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+        let js = ctx.generate_js().unwrap();
+
+        assert!(js.contains("export async function run({ message, env, globals = {} }) {"));
+        assert!(js.contains(r#"globals["count"] = "1";"#));
+        assert!(js.contains(r#"globals["last"] = `${globals["count"] ?? ""}`;"#));
+    }
+
+    #[test]
+    fn test_generate_set_script() {
+        let script = sieve::Compiler::new()
+            .compile(br#"require "variables"; set :upperfirst :lower "a" "HELLO";"#)
+            .unwrap();
+        let mut ctx = CodeGen::new(GenerateOpts::default(), &script.instructions);
+        let js = ctx.generate_js().unwrap();
+
+        assert!(
+            js.contains(r#"variables["local0"] = sieveUpperFirst(String("HELLO").toLowerCase());"#)
+        );
+        assert!(js.contains(SIEVE_UPPER_FIRST));
+    }
+}
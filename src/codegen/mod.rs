@@ -1,8 +1,213 @@
 mod buffer;
 pub(crate) mod js;
 
+use std::path::PathBuf;
+use std::str::FromStr;
+
 #[derive(Default, Clone)]
 pub(crate) struct GenerateOpts {
     pub(crate) debug: bool,
+    /// Destination of the kept messages, an address or `r2://BUCKET`.
+    pub(crate) keep: Option<String>,
     pub(crate) vacation_from_address: Option<String>,
+    /// KV namespace tracking the vacation responses.
+    pub(crate) vacation_kv: Option<String>,
+    /// Where the spamtest score comes from, messages are considered not
+    /// tested without one.
+    pub(crate) spamtest: Option<ScoreSource>,
+    /// Where the virustest score comes from, likewise.
+    pub(crate) virustest: Option<ScoreSource>,
+    /// Where the external lists (RFC 6134) are looked up.
+    pub(crate) lists: Option<ListSource>,
+    /// Where the personal scripts of `include` (RFC 6609) are read from.
+    pub(crate) include_dir: Option<PathBuf>,
+    /// Where the `:global` scripts of `include` are read from.
+    pub(crate) global_include_dir: Option<PathBuf>,
+}
+
+/// A classifier returning a score between 0 and 1 for a message.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ScoreSource {
+    /// A numeric header added by an upstream filter, such as `X-Spam-Score`,
+    /// scaled from `0..max`.
+    Header { name: String, max: f64 },
+    /// A Workers AI text classification model, the score is the one of
+    /// `label`.
+    WorkersAi {
+        binding: String,
+        model: String,
+        label: String,
+    },
+    /// A service binding, receiving the raw message in a POST request and
+    /// answering with the score.
+    Binding(String),
+    /// The default export of a JavaScript module, called with the message.
+    Module(String),
+}
+
+impl FromStr for ScoreSource {
+    type Err = String;
+
+    /// Parses `header:NAME[:MAX]`, `ai:BINDING:MODEL[:LABEL]`,
+    /// `binding:NAME` or `module:PATH`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, args) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid score source: {s}"))?;
+
+        match kind {
+            "header" => {
+                // The score is divided by the maximum, it must be a positive
+                // number.
+                let (name, max) = match args.split_once(':') {
+                    Some((name, max)) => (
+                        name,
+                        max.parse::<f64>()
+                            .ok()
+                            .filter(|max| max.is_finite() && *max > 0.0)
+                            .ok_or_else(|| format!("invalid score maximum: {max}"))?,
+                    ),
+                    None => (args, 10.0),
+                };
+                Ok(Self::Header {
+                    name: name.to_owned(),
+                    max,
+                })
+            }
+            "ai" => {
+                let mut args = args.splitn(3, ':');
+                match (args.next(), args.next(), args.next()) {
+                    (Some(binding), Some(model), label) if !binding.is_empty() => {
+                        Ok(Self::WorkersAi {
+                            binding: binding.to_owned(),
+                            model: model.to_owned(),
+                            label: label.unwrap_or("SPAM").to_owned(),
+                        })
+                    }
+                    _ => Err(format!("invalid Workers AI score source: {s}")),
+                }
+            }
+            "binding" => Ok(Self::Binding(args.to_owned())),
+            "module" => Ok(Self::Module(args.to_owned())),
+            _ => Err(format!("invalid score source: {s}")),
+        }
+    }
+}
+
+/// Storage of the external lists, each list name maps to a JSON array of
+/// addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ListSource {
+    /// A KV namespace, keyed by list name.
+    Kv(String),
+    /// A JSON object in a variable of the Worker.
+    Json(String),
+}
+
+impl FromStr for ListSource {
+    type Err = String;
+
+    /// Parses `kv:BINDING` or `json:BINDING`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("kv", binding)) if !binding.is_empty() => Ok(Self::Kv(binding.to_owned())),
+            Some(("json", binding)) if !binding.is_empty() => Ok(Self::Json(binding.to_owned())),
+            _ => Err(format!("invalid list source: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score_source_from_str() {
+        let test_cases = &[
+            (
+                "header:X-Spam-Score",
+                Ok(ScoreSource::Header {
+                    name: "X-Spam-Score".to_owned(),
+                    max: 10.0,
+                }),
+            ),
+            (
+                "header:X-Spam-Probability:1",
+                Ok(ScoreSource::Header {
+                    name: "X-Spam-Probability".to_owned(),
+                    max: 1.0,
+                }),
+            ),
+            (
+                "ai:AI:@cf/example/spam-classifier",
+                Ok(ScoreSource::WorkersAi {
+                    binding: "AI".to_owned(),
+                    model: "@cf/example/spam-classifier".to_owned(),
+                    label: "SPAM".to_owned(),
+                }),
+            ),
+            (
+                "ai:AI:@cf/huggingface/distilbert-sst-2-int8:NEGATIVE",
+                Ok(ScoreSource::WorkersAi {
+                    binding: "AI".to_owned(),
+                    model: "@cf/huggingface/distilbert-sst-2-int8".to_owned(),
+                    label: "NEGATIVE".to_owned(),
+                }),
+            ),
+            (
+                "binding:SCANNER",
+                Ok(ScoreSource::Binding("SCANNER".to_owned())),
+            ),
+            (
+                "module:./score.js",
+                Ok(ScoreSource::Module("./score.js".to_owned())),
+            ),
+            (
+                "header:X-Spam-Score:high",
+                Err("invalid score maximum: high".to_owned()),
+            ),
+            (
+                "header:X-Spam-Score:0",
+                Err("invalid score maximum: 0".to_owned()),
+            ),
+            (
+                "header:X-Spam-Score:-5",
+                Err("invalid score maximum: -5".to_owned()),
+            ),
+            (
+                "header:X-Spam-Score:inf",
+                Err("invalid score maximum: inf".to_owned()),
+            ),
+            (
+                "header:X-Spam-Score:NaN",
+                Err("invalid score maximum: NaN".to_owned()),
+            ),
+            (
+                "ai:AI",
+                Err("invalid Workers AI score source: ai:AI".to_owned()),
+            ),
+            (
+                "X-Spam-Score",
+                Err("invalid score source: X-Spam-Score".to_owned()),
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(&input.parse::<ScoreSource>(), expected);
+        }
+    }
+
+    #[test]
+    fn test_list_source_from_str() {
+        let test_cases = &[
+            ("kv:LISTS", Ok(ListSource::Kv("LISTS".to_owned()))),
+            ("json:LISTS", Ok(ListSource::Json("LISTS".to_owned()))),
+            ("kv:", Err("invalid list source: kv:".to_owned())),
+            ("LISTS", Err("invalid list source: LISTS".to_owned())),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(&input.parse::<ListSource>(), expected);
+        }
+    }
 }
@@ -11,7 +11,7 @@ pub fn compile_sieve_to_js(contents: &str) -> Result<String, BoxError> {
     let js = {
         let opts = codegen::GenerateOpts {
             debug: false,
-            vacation_from_address: None,
+            ..codegen::GenerateOpts::default()
         };
         let mut code_gen = codegen::js::CodeGen::new(opts, &script.instructions);
         code_gen
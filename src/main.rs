@@ -1,6 +1,7 @@
 use clap::Parser;
 use std::fs;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::process;
 
 mod codegen;
@@ -22,9 +23,42 @@ struct Args {
     /// Generate debug code in the Cloudflare Worker
     debug: bool,
 
-    /// Email used when sending a Vacation reply
+    /// Where kept messages go, an address to forward them to or an R2 bucket
+    /// (`r2://BUCKET`). The `SIEVE_KEEP` variable of the Worker overrides it
+    #[arg(long)]
+    keep: Option<String>,
+
+    /// Email used when sending a Vacation reply without `:from`, the
+    /// recipient of the message by default
     #[arg(long)]
     vacation_from_address: Option<String>,
+
+    /// KV namespace binding tracking the Vacation replies, so that a sender
+    /// gets one reply per period
+    #[arg(long)]
+    vacation_kv: Option<String>,
+
+    /// Score source of the spamtest test: `header:NAME[:MAX]`,
+    /// `ai:BINDING:MODEL[:LABEL]`, `binding:NAME` or `module:PATH`
+    #[arg(long)]
+    spamtest: Option<codegen::ScoreSource>,
+
+    /// Where external lists are looked up: `kv:BINDING` or `json:BINDING`
+    #[arg(long)]
+    lists: Option<codegen::ListSource>,
+
+    /// Score source of the virustest test, same format as --spamtest
+    #[arg(long)]
+    virustest: Option<codegen::ScoreSource>,
+
+    /// Directory of the scripts included with `include`, as `NAME.sieve`.
+    /// The directory of the input file by default
+    #[arg(long)]
+    include_dir: Option<PathBuf>,
+
+    /// Directory of the scripts included with `include :global`
+    #[arg(long)]
+    global_include_dir: Option<PathBuf>,
 }
 
 fn main() {
@@ -51,10 +85,19 @@ fn inner_main() -> Result<(), BoxError> {
     let js = {
         let opts = codegen::GenerateOpts {
             debug: args.debug,
+            keep: args.keep,
             vacation_from_address: args.vacation_from_address,
+            vacation_kv: args.vacation_kv,
+            spamtest: args.spamtest,
+            virustest: args.virustest,
+            lists: args.lists,
+            include_dir: args
+                .include_dir
+                .or_else(|| Path::new(&args.input).parent().map(Path::to_path_buf)),
+            global_include_dir: args.global_include_dir,
         };
         let mut code_gen = codegen::js::CodeGen::new(opts, &script.instructions);
-        code_gen.generate_js().unwrap()
+        code_gen.generate_js()?
     };
 
     let out_file = if let Some(output) = &args.output {
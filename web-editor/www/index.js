@@ -35,9 +35,9 @@ if not header :matches "X-Spam-Known-Sender" "yes*" {
   ) {
     set "spam" "Y";
   }
-  # if header :value "ge" :comparator "i;ascii-numeric" "X-Spam-score" "5" {
-  #   set "spam" "Y";
-  # }
+  if header :value "ge" :comparator "i;ascii-numeric" "X-Spam-score" "5" {
+    set "spam" "Y";
+  }
 }
 ### }}}
 